@@ -0,0 +1,282 @@
+use crate::identity::{node_id_for, Identity};
+use crate::utils::{hash, unix_time};
+use prost::Message;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tonic::metadata::{BinaryMetadataValue, MetadataMap, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+const PUBLIC_KEY_HEADER: &str = "ufs-public-key-bin";
+const TIMESTAMP_HEADER: &str = "ufs-timestamp";
+const NONCE_HEADER: &str = "ufs-nonce-bin";
+const DIGEST_HEADER: &str = "ufs-digest-bin";
+const SIGNATURE_HEADER: &str = "ufs-signature-bin";
+
+/// How far a request's timestamp may be from our clock, in seconds. Nonces
+/// are remembered for this long, so a signed request can't be replayed.
+pub const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+// how many nonces are remembered at most; signed requests beyond this within
+// the skew window are refused rather than letting the set grow
+const MAX_SEEN_NONCES: usize = 1_000_000;
+
+/// The verified sender of a signed request, attached to it by
+/// [`Authenticator`].
+#[derive(Clone, Debug)]
+pub struct SignedBy {
+    pub node_id: [u8; 32],
+    digest: Vec<u8>,
+}
+
+/// Hashes a request payload together with the RPC it is meant for, so a
+/// signature can't be moved to another method.
+fn payload_digest<T: Message>(method: &str, message: &T) -> Vec<u8> {
+    let mut data = method.as_bytes().to_vec();
+    data.push(0);
+    data.extend(message.encode_to_vec());
+    hash(&data)
+}
+
+/// The bytes a request signature covers.
+fn signed_bytes(timestamp: u64, nonce: &[u8], digest: &[u8]) -> Vec<u8> {
+    let mut data = timestamp.to_le_bytes().to_vec();
+    data.extend_from_slice(nonce);
+    data.extend_from_slice(digest);
+    data
+}
+
+/// Wraps `message` in a request for `method`, signed with our node key.
+pub fn signed_request<T: Message>(identity: &Identity, method: &str, message: T) -> Request<T> {
+    let timestamp = unix_time();
+    let nonce: [u8; 16] = rand::random();
+    let digest = payload_digest(method, &message);
+    let signature = identity.sign(&signed_bytes(timestamp, &nonce, &digest));
+
+    let mut request = Request::new(message);
+    let metadata = request.metadata_mut();
+    metadata.insert_bin(
+        PUBLIC_KEY_HEADER,
+        MetadataValue::from_bytes(&identity.public_key()),
+    );
+    metadata.insert(TIMESTAMP_HEADER, timestamp.into());
+    metadata.insert_bin(NONCE_HEADER, MetadataValue::from_bytes(&nonce));
+    metadata.insert_bin(DIGEST_HEADER, MetadataValue::from_bytes(&digest));
+    metadata.insert_bin(SIGNATURE_HEADER, MetadataValue::from_bytes(&signature));
+    request
+}
+
+/// Checks that `request` was signed for `method` and returns its sender.
+///
+/// The signature itself is verified by [`Authenticator`]; this ties it to the
+/// payload the handler actually received.
+pub fn verify_sender<T: Message>(request: &Request<T>, method: &str) -> Result<SignedBy, Status> {
+    let signer = request
+        .extensions()
+        .get::<SignedBy>()
+        .ok_or_else(|| Status::unauthenticated("request must be signed by a node key"))?;
+    if signer.digest != payload_digest(method, request.get_ref()) {
+        return Err(Status::unauthenticated(
+            "signature does not cover this request",
+        ));
+    }
+    Ok(signer.clone())
+}
+
+/// Server interceptor verifying signed requests.
+///
+/// Unsigned requests pass through untouched, since local clients talk to the
+/// same service; handlers for peer-to-peer RPCs refuse them through
+/// [`verify_sender`]. A request that carries a signature must have a fresh
+/// timestamp, an unseen nonce and a valid signature from the key it presents.
+#[derive(Clone, Default)]
+pub struct Authenticator {
+    seen_nonces: Arc<Mutex<SeenNonces>>,
+}
+
+/// The nonces of signed requests still within the skew window, bucketed by
+/// request timestamp so expired ones are dropped a whole second at a time.
+#[derive(Default)]
+struct SeenNonces {
+    buckets: BTreeMap<u64, HashSet<Vec<u8>>>,
+    len: usize,
+}
+
+impl SeenNonces {
+    /// Records `nonce`, failing if it was already seen or the set is full.
+    fn insert(&mut self, nonce: Vec<u8>, timestamp: u64, now: u64) -> Result<(), Status> {
+        // requests older than this fail the timestamp check anyway
+        let keep = self.buckets.split_off(&now.saturating_sub(MAX_CLOCK_SKEW));
+        let expired = std::mem::replace(&mut self.buckets, keep);
+        self.len -= expired.values().map(HashSet::len).sum::<usize>();
+
+        let bucket = self.buckets.entry(timestamp).or_default();
+        if bucket.contains(&nonce) {
+            return Err(Status::unauthenticated("replayed request"));
+        }
+        if self.len >= MAX_SEEN_NONCES {
+            return Err(Status::resource_exhausted("too many signed requests"));
+        }
+        bucket.insert(nonce);
+        self.len += 1;
+        Ok(())
+    }
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn verify(&self, metadata: &MetadataMap) -> Result<SignedBy, Status> {
+        let public_key: [u8; 32] = binary_header(metadata, PUBLIC_KEY_HEADER)?
+            .try_into()
+            .map_err(|_| Status::unauthenticated("public key must be 32 bytes"))?;
+        let timestamp: u64 = metadata
+            .get(TIMESTAMP_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| Status::unauthenticated("missing or malformed timestamp"))?;
+        let nonce = binary_header(metadata, NONCE_HEADER)?;
+        let digest = binary_header(metadata, DIGEST_HEADER)?;
+        let signature = binary_header(metadata, SIGNATURE_HEADER)?;
+
+        let now = unix_time();
+        if timestamp.abs_diff(now) > MAX_CLOCK_SKEW {
+            return Err(Status::unauthenticated("request timestamp is out of range"));
+        }
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(&signed_bytes(timestamp, &nonce, &digest), &signature)
+            .map_err(|_| Status::unauthenticated("invalid request signature"))?;
+
+        self.seen_nonces
+            .lock()
+            .unwrap()
+            .insert(nonce, timestamp, now)?;
+
+        Ok(SignedBy {
+            node_id: node_id_for(&public_key),
+            digest,
+        })
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if request.metadata().get_bin(SIGNATURE_HEADER).is_none() {
+            return Ok(request);
+        }
+        let signer = self.verify(request.metadata())?;
+        request.extensions_mut().insert(signer);
+        Ok(request)
+    }
+}
+
+fn binary_header(metadata: &MetadataMap, key: &str) -> Result<Vec<u8>, Status> {
+    metadata
+        .get_bin(key)
+        .map(BinaryMetadataValue::to_bytes)
+        .and_then(Result::ok)
+        .map(|bytes| bytes.to_vec())
+        .ok_or_else(|| Status::unauthenticated(format!("missing or malformed {}", key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_proto::FindNodeRequest;
+
+    /// Runs `request` through the interceptor the way the server would.
+    fn intercept(
+        auth: &mut Authenticator,
+        request: Request<FindNodeRequest>,
+    ) -> Result<Request<FindNodeRequest>, Status> {
+        let (metadata, extensions, message) = request.into_parts();
+        let (metadata, extensions, ()) = auth
+            .call(Request::from_parts(metadata, extensions, ()))?
+            .into_parts();
+        Ok(Request::from_parts(metadata, extensions, message))
+    }
+
+    fn find_node(target: u8) -> FindNodeRequest {
+        FindNodeRequest {
+            target_id: vec![target; 32],
+        }
+    }
+
+    #[test]
+    fn signed_request_is_attributed_to_its_sender() {
+        let identity = Identity::generate().unwrap();
+        let mut auth = Authenticator::new();
+        let request = intercept(
+            &mut auth,
+            signed_request(&identity, "FindNode", find_node(1)),
+        );
+        let signer = verify_sender(&request.unwrap(), "FindNode").unwrap();
+        assert_eq!(signer.node_id, identity.node_id());
+    }
+
+    #[test]
+    fn unsigned_requests_pass_but_have_no_sender() {
+        let mut auth = Authenticator::new();
+        let request = intercept(&mut auth, Request::new(find_node(1))).unwrap();
+        assert!(verify_sender(&request, "FindNode").is_err());
+    }
+
+    #[test]
+    fn replayed_request_is_rejected() {
+        let identity = Identity::generate().unwrap();
+        let mut auth = Authenticator::new();
+        let request = signed_request(&identity, "FindNode", find_node(1));
+        let replay =
+            Request::from_parts(request.metadata().clone(), Default::default(), find_node(1));
+        assert!(intercept(&mut auth, request).is_ok());
+        assert!(intercept(&mut auth, replay).is_err());
+    }
+
+    #[test]
+    fn signature_is_bound_to_payload_and_method() {
+        let identity = Identity::generate().unwrap();
+        let mut auth = Authenticator::new();
+
+        let (metadata, extensions, _) =
+            signed_request(&identity, "FindNode", find_node(1)).into_parts();
+        let tampered = Request::from_parts(metadata, extensions, find_node(2));
+        let tampered = intercept(&mut auth, tampered).unwrap();
+        assert!(verify_sender(&tampered, "FindNode").is_err());
+
+        let request = intercept(
+            &mut auth,
+            signed_request(&identity, "FindNode", find_node(1)),
+        );
+        assert!(verify_sender(&request.unwrap(), "FindValue").is_err());
+    }
+
+    #[test]
+    fn forged_signature_is_rejected() {
+        let identity = Identity::generate().unwrap();
+        let other = Identity::generate().unwrap();
+        let mut auth = Authenticator::new();
+        let mut request = signed_request(&identity, "FindNode", find_node(1));
+        request.metadata_mut().insert_bin(
+            PUBLIC_KEY_HEADER,
+            MetadataValue::from_bytes(&other.public_key()),
+        );
+        assert!(intercept(&mut auth, request).is_err());
+    }
+
+    #[test]
+    fn nonces_are_forgotten_once_outside_the_skew_window() {
+        let mut seen = SeenNonces::default();
+        let now = 1_000_000;
+        seen.insert(vec![1], now, now).unwrap();
+        seen.insert(vec![2], now + 1, now).unwrap();
+        assert!(seen.insert(vec![1], now, now + 1).is_err());
+
+        seen.insert(vec![3], now + MAX_CLOCK_SKEW + 1, now + MAX_CLOCK_SKEW + 1)
+            .unwrap();
+        assert_eq!(seen.len, 2);
+        assert_eq!(seen.buckets.len(), 2);
+    }
+}
@@ -0,0 +1,186 @@
+use std::str::FromStr;
+
+/// The size of the chunks files are cut into, the last one excepted, unless
+/// they use content-defined chunking. Also the shard size of erasure-coded
+/// files.
+pub const CHUNK_SIZE: usize = 1024 * 256;
+
+/// Chunk size bounds for content-defined chunking, written `MIN:AVG:MAX`
+/// with optional `k`/`m` suffixes, e.g. `64k:256k:1m`.
+///
+/// Chunk boundaries are placed where a rolling hash of the last bytes hits a
+/// pattern, so they move along with the content: inserting a byte near the
+/// start of a file only changes the chunks around the insertion, and the
+/// rest still hash to chunks the network already stores.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CdcParams {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+// the largest chunk we'll cut; whole chunks travel in one gRPC message
+const MAX_CHUNK_SIZE: usize = 2 * 1024 * 1024;
+
+impl FromStr for CdcParams {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sizes: Vec<usize> = s.split(':').map(parse_size).collect::<Result<_, _>>()?;
+        let [min_size, avg_size, max_size] = sizes[..] else {
+            return Err(format!("expected MIN:AVG:MAX, e.g. 64k:256k:1m, got {}", s));
+        };
+        if !(64 <= min_size && min_size < avg_size && avg_size < max_size) {
+            return Err("sizes must satisfy 64 <= MIN < AVG < MAX".into());
+        }
+        if max_size > MAX_CHUNK_SIZE {
+            return Err(format!("MAX can be {} bytes at most", MAX_CHUNK_SIZE));
+        }
+        Ok(Self {
+            min_size,
+            avg_size,
+            max_size,
+        })
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    let lower = s.to_ascii_lowercase();
+    let (digits, unit) = if let Some(digits) = lower.strip_suffix('k') {
+        (digits, 1024)
+    } else if let Some(digits) = lower.strip_suffix('m') {
+        (digits, 1024 * 1024)
+    } else {
+        (lower.as_str(), 1)
+    };
+    digits
+        .parse::<usize>()
+        .map(|n| n * unit)
+        .map_err(|_| format!("invalid size {}", s))
+}
+
+impl CdcParams {
+    /// Returns the length of the next chunk at the start of `data`. `data`
+    /// must hold at least `max_size` bytes unless it is the end of the file.
+    ///
+    /// This is FastCDC: a gear hash is rolled over the bytes past `min_size`,
+    /// and a boundary falls where its top bits are all zero. Before
+    /// `avg_size` two more bits have to be zero than after it, which keeps
+    /// chunk sizes close to the average.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let end = data.len().min(self.max_size);
+        let normal = end.min(self.avg_size);
+        let bits = self.avg_size.ilog2();
+        let (strict, loose) = (top_bits(bits + 1), top_bits(bits - 1));
+
+        let mut fingerprint = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(end).skip(self.min_size) {
+            fingerprint = (fingerprint << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < normal { strict } else { loose };
+            if fingerprint & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// A mask of the `n` most significant bits. The gear hash shifts left, so
+/// its top bits depend on the most recent 64 bytes.
+fn top_bits(n: u32) -> u64 {
+    !(u64::MAX >> n.min(64))
+}
+
+/// Random values for every byte, fixed so that every node cuts identical
+/// data at the same places.
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 from a fixed seed
+    let mut table = [0; 256];
+    let mut state: u64 = 0x5546_5343_4443_0001;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: CdcParams = CdcParams {
+        min_size: 1024,
+        avg_size: 4096,
+        max_size: 16384,
+    };
+
+    fn split(mut data: &[u8]) -> Vec<&[u8]> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let (chunk, rest) = data.split_at(PARAMS.cut(data));
+            chunks.push(chunk);
+            data = rest;
+        }
+        chunks
+    }
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_sizes() {
+        let params: CdcParams = "16k:64k:1m".parse().unwrap();
+        assert_eq!(params.min_size, 16 * 1024);
+        assert_eq!(params.avg_size, 64 * 1024);
+        assert_eq!(params.max_size, 1024 * 1024);
+        assert!("64k:16k:1m".parse::<CdcParams>().is_err());
+        assert!("16k:64k".parse::<CdcParams>().is_err());
+        assert!("16k:64k:8m".parse::<CdcParams>().is_err());
+    }
+
+    #[test]
+    fn chunks_stay_within_bounds() {
+        let data = pseudo_random(1 << 20);
+        let chunks = split(&data);
+        assert_eq!(chunks.concat(), data);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(last.len() <= PARAMS.max_size);
+        assert!(rest
+            .iter()
+            .all(|c| c.len() >= PARAMS.min_size && c.len() <= PARAMS.max_size));
+        // roughly the average size
+        assert!(chunks.len() > data.len() / (2 * PARAMS.avg_size));
+        assert!(chunks.len() < data.len() / (PARAMS.avg_size / 2));
+    }
+
+    #[test]
+    fn insertion_only_changes_nearby_chunks() {
+        let data = pseudo_random(1 << 20);
+        let mut edited = vec![42];
+        edited.extend_from_slice(&data);
+
+        let before: std::collections::HashSet<&[u8]> = split(&data).into_iter().collect();
+        let after = split(&edited);
+        let reused = after.iter().filter(|c| before.contains(*c)).count();
+        assert!(reused + 2 >= after.len());
+    }
+}
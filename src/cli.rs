@@ -1,25 +1,49 @@
+use crate::chunking::{CdcParams, CHUNK_SIZE};
+use crate::download::{PartialDownload, Scheduler, BATCH_SIZE};
+use crate::erasure::{self, ErasureParams};
+use crate::manifest::{self, ManifestBuilder};
 use crate::storage_proto::peer_service_client::PeerServiceClient;
 use crate::storage_proto::{
-    FileInfo, GetChunkRequest, GetFileMetadataRequest, InitiateUploadRequest, StoreRequest,
+    CdcLayout, DirectoryEntry, DirectoryInfo, FileInfo, FindProvidersRequest,
+    InitiateUploadRequest, ProvideRequest, ReadRangeRequest, ReplicateFileRequest, StripeLayout,
     UploadChunkRequest,
 };
 use crate::utils::hash;
 use crate::CliCommands;
-use std::fs;
+use futures::stream::StreamExt;
+use std::collections::VecDeque;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
 use tonic::Request;
 
+// chunks read ahead of what the upload stream has sent
+const UPLOAD_QUEUE: usize = 4;
+
 pub async fn handle_cli_command(
     node_addr: String,
     command: CliCommands,
 ) -> Result<(), Box<dyn std::error::Error>> {
     match command {
-        CliCommands::Upload { path } => {
-            upload_file(&node_addr, path).await?;
+        CliCommands::Upload { path, erasure, cdc } => {
+            upload_file(&node_addr, path, erasure, cdc).await?;
+        }
+        CliCommands::Download {
+            hash,
+            output,
+            window,
+        } => {
+            download_file(&node_addr, &hash, output, window).await?;
         }
-        CliCommands::Download { hash, output } => {
-            download_file(&node_addr, &hash, output).await?;
+        CliCommands::Cat {
+            hash,
+            offset,
+            length,
+        } => {
+            cat_file(&node_addr, &hash, offset, length).await?;
         }
         CliCommands::ListFiles => {
             let mut client = PeerServiceClient::connect(node_addr).await?;
@@ -60,28 +84,162 @@ pub async fn handle_cli_command(
                 println!("- {}", hex::encode(chunk));
             }
         }
+        CliCommands::RepairStats => {
+            let mut client = PeerServiceClient::connect(node_addr).await?;
+            let stats = client
+                .get_repair_stats(tonic::Request::new(
+                    crate::storage_proto::GetRepairStatsRequest {},
+                ))
+                .await?
+                .into_inner();
+            println!("Repair rounds: {}", stats.rounds);
+            println!("Chunks checked: {}", stats.chunks_checked);
+            println!("Under-replicated chunks found: {}", stats.under_replicated);
+            println!("Replicas pushed: {}", stats.replicas_pushed);
+            println!("Failed pushes: {}", stats.failed_pushes);
+        }
     }
 
     Ok(())
 }
 
-async fn upload_file(node_addr: &str, path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+async fn upload_file(
+    node_addr: &str,
+    path: PathBuf,
+    erasure: Option<ErasureParams>,
+    cdc: Option<CdcParams>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let mut client = PeerServiceClient::connect(node_addr.to_string()).await?;
+    let is_dir = path.is_dir();
+    let (file_hash, size) = upload_path(&mut client, path, erasure, cdc).await?;
+    if is_dir {
+        println!(
+            "Directory uploaded, {} bytes in total. Root hash: {}",
+            size,
+            hex::encode(file_hash)
+        );
+    }
+    Ok(())
+}
+
+/// Uploads a file, or a directory with everything below it, and returns
+/// its hash and size. A directory is stored after its entries, as a
+/// `FileInfo` listing their hashes, so its hash covers the whole tree.
+async fn upload_path(
+    client: &mut PeerServiceClient<Channel>,
+    path: PathBuf,
+    erasure: Option<ErasureParams>,
+    cdc: Option<CdcParams>,
+) -> Result<([u8; 32], u64), Box<dyn std::error::Error>> {
+    let name = path
+        .file_name()
+        .ok_or("path has no file name")?
+        .to_string_lossy()
+        .into();
+    if !std::fs::metadata(&path)?.is_dir() {
+        println!("Uploading {}", path.display());
+        return upload_file_contents(client, path, name, erasure, cdc).await;
+    }
 
-    let data = fs::read(&path)?;
-    // 256kb chunks
-    let chunk_size = 1024 * 256;
-    let chunks: Vec<Vec<u8>> = data.chunks(chunk_size).map(|c| c.to_vec()).collect();
-    // hash the chunks
-    let chunk_hashes: Vec<Vec<u8>> = chunks.iter().map(|c| hash(c)).collect();
-    // store the metadata
+    // sorted, so the same tree always hashes the same
+    let mut children: Vec<_> = std::fs::read_dir(&path)?.collect::<Result<_, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+    let mut entries = Vec::with_capacity(children.len());
+    for child in children {
+        let metadata = child.metadata()?;
+        if !metadata.is_dir() && !metadata.is_file() {
+            println!(
+                "Skipping {}, not a file or directory",
+                child.path().display()
+            );
+            continue;
+        }
+        let (hash, size) = Box::pin(upload_path(client, child.path(), erasure, cdc)).await?;
+        entries.push(DirectoryEntry {
+            name: child.file_name().to_string_lossy().into(),
+            mode: file_mode(&metadata),
+            size,
+            hash: hash.to_vec(),
+        });
+    }
+
+    let metadata = FileInfo {
+        name,
+        size: entries.iter().map(|entry| entry.size).sum(),
+        directory: Some(DirectoryInfo { entries }),
+        ..Default::default()
+    };
+    let size = metadata.size;
+    Ok((publish(client, metadata).await?, size))
+}
+
+/// Uploads the chunks of one file and publishes its metadata.
+async fn upload_file_contents(
+    client: &mut PeerServiceClient<Channel>,
+    path: PathBuf,
+    name: String,
+    erasure: Option<ErasureParams>,
+    cdc: Option<CdcParams>,
+) -> Result<([u8; 32], u64), Box<dyn std::error::Error>> {
+    // the file is read and sent a chunk at a time, never held in memory
+    let (tx, rx) = mpsc::channel(UPLOAD_QUEUE);
+    let reader = tokio::spawn(read_chunks(path, erasure, cdc, tx));
+    let uploaded = client
+        .upload_chunks(ReceiverStream::new(rx))
+        .await?
+        .into_inner();
+    let (size, chunk_hashes, chunk_sizes, manifest_depth) =
+        reader.await?.map_err(|e| e as Box<dyn std::error::Error>)?;
+    println!(
+        "Uploaded {} chunks, {} of them already stored.",
+        uploaded.chunks_stored + uploaded.chunks_reused,
+        uploaded.chunks_reused
+    );
+
+    // either plain chunks, or the data and parity shards of each stripe
+    let stripes = erasure.map(|params| {
+        println!(
+            "Erasure-coded into {} stripes of {}+{} shards.",
+            size.div_ceil((CHUNK_SIZE * params.data_shards) as u64),
+            params.data_shards,
+            params.parity_shards
+        );
+        StripeLayout {
+            data_shards: params.data_shards as u32,
+            parity_shards: params.parity_shards as u32,
+            shard_size: CHUNK_SIZE as u64,
+        }
+    });
     let metadata = FileInfo {
-        name: path.file_name().unwrap().to_string_lossy().into(),
-        size: data.len() as u64,
-        chunk_hashes: chunk_hashes.clone(),
+        name,
+        size,
+        chunk_hashes,
+        stripes,
+        cdc: cdc.map(|params| CdcLayout {
+            min_size: params.min_size as u32,
+            avg_size: params.avg_size as u32,
+            max_size: params.max_size as u32,
+        }),
+        manifest_depth,
+        directory: None,
+        // only content-defined chunks need their sizes to be found
+        chunk_sizes: if cdc.is_some() {
+            chunk_sizes
+        } else {
+            Vec::new()
+        },
     };
-    // we hash the entire metadata and store it as file hash
-    let file_hash_vec = hash(&bincode::serialize(&metadata)?);
+    Ok((publish(client, metadata).await?, size))
+}
+
+/// Stores `metadata` on the node, announces it and replicates it along
+/// with its chunks. Returns the file hash.
+async fn publish(
+    client: &mut PeerServiceClient<Channel>,
+    metadata: FileInfo,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    // the file hash covers the same encoding the nodes verify and store
+    let file_hash_vec = hash(&crate::storage::FileInfo::from(metadata.clone()).encode());
     let file_hash: [u8; 32] = file_hash_vec.as_slice().try_into().unwrap();
 
     // Store the file locally
@@ -92,94 +250,420 @@ async fn upload_file(node_addr: &str, path: PathBuf) -> Result<(), Box<dyn std::
         }))
         .await?;
 
-    // store the chunks locally
-    for (chunk, hash) in chunks.iter().zip(chunk_hashes.iter()) {
-        client
-            .upload_chunk(Request::new(UploadChunkRequest {
-                chunk_hash: hash.clone(),
-                chunk_data: chunk.clone(),
-            }))
-            .await?;
-    }
-
     println!("File uploaded locally. Hash: {}", hex::encode(file_hash));
 
-    // Find k-closest nodes to the file hash
-    let mut find_client = PeerServiceClient::connect(node_addr.to_string()).await?;
-    let find_node_response = find_client
-        .find_node(Request::new(crate::storage_proto::FindNodeRequest {
-            target_id: file_hash.to_vec(),
+    // Ask the node to announce itself as a provider of the file to the
+    // k-closest nodes to the file hash
+    let announced = client
+        .provide(Request::new(ProvideRequest {
+            key: file_hash.to_vec(),
         }))
-        .await?;
+        .await?
+        .into_inner()
+        .announced;
+    println!("Announced file to {} peers.", announced);
 
-    let closest_peers = find_node_response.into_inner().peers;
+    // copy the metadata and chunks to the peers closest to them, so the file
+    // survives this node going away
+    let report = client
+        .replicate_file(Request::new(ReplicateFileRequest {
+            file_hash: file_hash.to_vec(),
+        }))
+        .await?
+        .into_inner();
+    let metadata_acks = report.metadata.map_or(0, |acks| acks.peers.len());
+    println!(
+        "Replicated metadata to {}/{} peers.",
+        metadata_acks, report.target
+    );
+    let shards = if report.shards > 0 {
+        ", shards to one peer each"
+    } else {
+        ""
+    };
     println!(
-        "Found {} closest peers to announce the file to.",
-        closest_peers.len()
+        "Replicated {}/{} chunks to {} peers each{}.",
+        report.chunks - report.under_replicated,
+        report.chunks,
+        report.target,
+        shards
     );
+    for acks in &report.under_replicated_chunks {
+        println!(
+            "- chunk {} only reached {} of {} peers",
+            hex::encode(&acks.key),
+            acks.peers.len(),
+            acks.target
+        );
+    }
+    let unlisted = report.under_replicated - report.under_replicated_chunks.len() as u64;
+    if unlisted > 0 {
+        println!("- and {} more chunks", unlisted);
+    }
 
-    // Send a Store Request to each of the k-closest nodes
-    // telling them that we have the file
-    for peer in closest_peers {
-        println!("Announcing file to peer at {}", peer.address);
-        let mut store_client = PeerServiceClient::connect(peer.address).await?;
-        store_client
-            .store(Request::new(StoreRequest {
-                key: file_hash.to_vec(),
-                value: node_addr.to_string(),
-            }))
-            .await?;
+    Ok(file_hash)
+}
+
+/// Reads a file chunk by chunk, or a stripe at a time when erasure-coding,
+/// and queues every chunk for upload as soon as it is hashed, along with the
+/// manifest nodes listing them. Returns the file size, the hashes for
+/// `FileInfo.chunk_hashes` with the bytes under each, and the manifest depth.
+async fn read_chunks(
+    path: PathBuf,
+    erasure: Option<ErasureParams>,
+    cdc: Option<CdcParams>,
+    tx: mpsc::Sender<UploadChunkRequest>,
+) -> Result<(u64, Vec<Vec<u8>>, Vec<u64>, u32), Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = tokio::fs::File::open(&path).await?;
+    // enough for a chunk, a stripe of data shards, or the largest
+    // content-defined chunk
+    let read_size = match (erasure, cdc) {
+        (Some(params), _) => CHUNK_SIZE * params.data_shards,
+        (_, Some(params)) => params.max_size,
+        _ => CHUNK_SIZE,
+    };
+    let mut buf = vec![0; read_size];
+    let mut filled = 0;
+    let mut size = 0;
+    let mut manifest = ManifestBuilder::new(manifest::FANOUT);
+    loop {
+        let n = read_full(&mut file, &mut buf[filled..]).await?;
+        size += n as u64;
+        filled += n;
+        if filled == 0 {
+            break;
+        }
+        let (chunks, used) = match (erasure, cdc) {
+            (Some(params), _) => (erasure::encode(&buf[..filled], CHUNK_SIZE, params)?, filled),
+            (_, Some(params)) => {
+                let len = params.cut(&buf[..filled]);
+                (vec![buf[..len].to_vec()], len)
+            }
+            _ => (vec![buf[..filled].to_vec()], filled),
+        };
+        for chunk in chunks {
+            let chunk_hash = hash(&chunk);
+            let nodes = manifest.push(chunk_hash.clone(), chunk.len());
+            send_chunk(&tx, chunk_hash, chunk).await?;
+            for node in nodes {
+                send_chunk(&tx, hash(&node), node).await?;
+            }
+        }
+        // a content-defined chunk leaves the rest of the buffer for the next
+        buf.copy_within(used..filled, 0);
+        filled -= used;
     }
 
-    Ok(())
+    let top = manifest.finish();
+    for node in top.nodes {
+        send_chunk(&tx, hash(&node), node).await?;
+    }
+    Ok((size, top.chunk_hashes, top.chunk_sizes, top.depth))
+}
+
+async fn send_chunk(
+    tx: &mpsc::Sender<UploadChunkRequest>,
+    chunk_hash: Vec<u8>,
+    chunk_data: Vec<u8>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = UploadChunkRequest {
+        chunk_hash,
+        chunk_data,
+    };
+    tx.send(request)
+        .await
+        .map_err(|_| "the node closed the upload".into())
+}
+
+/// The permission bits recorded for a directory entry.
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    let mode = std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o777;
+    #[cfg(not(unix))]
+    let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+    mode
+}
+
+/// Fills `buf` from `file`, returning less only at the end of the file.
+async fn read_full(file: &mut tokio::fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
 }
 
 async fn download_file(
     node_addr: &str,
     hash_str: &str,
     output: PathBuf,
+    window: usize,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let file_hash_vec = hex::decode(hash_str)?;
     let file_hash: [u8; 32] = file_hash_vec.as_slice().try_into().unwrap();
 
-    // find the value (provider address) for the file hash
-    let mut client = PeerServiceClient::connect(node_addr.to_string()).await?;
-    let find_value_response = client
-        .find_value(Request::new(crate::storage_proto::FindValueRequest {
-            key: file_hash.to_vec(),
-        }))
-        .await?;
-
-    let result = find_value_response.into_inner().result;
+    // a directory's entries are queued as it is listed and fetched like
+    // files of their own; the root has no entry
+    let mut queue = VecDeque::from([(file_hash, output, None::<DirectoryEntry>)]);
+    while let Some((file_hash, output, entry)) = queue.pop_front() {
+        // find every provider of the file hash
+        let mut client = PeerServiceClient::connect(node_addr.to_string()).await?;
+        let providers = client
+            .find_providers(Request::new(FindProvidersRequest {
+                key: file_hash.to_vec(),
+            }))
+            .await?
+            .into_inner()
+            .providers;
 
-    if let Some(crate::storage_proto::find_value_response::Result::Value(provider_addr)) = result {
-        println!("File provider found at: {}", provider_addr);
+        if providers.is_empty() {
+            if entry.is_some() {
+                return Err(format!("{} not found on the network", output.display()).into());
+            }
+            println!("File not found on the network.");
+            return Ok(());
+        }
+        println!("Found {} providers for the file.", providers.len());
 
-        // now connect to the provider and download the file
-        let mut provider_client = PeerServiceClient::connect(provider_addr).await?;
+        let scheduler = Scheduler::new(&providers, client, window);
+        let metadata = scheduler.fetch_metadata(&file_hash).await?;
+        match &metadata.directory {
+            Some(directory) => {
+                std::fs::create_dir_all(&output)?;
+                for child in &directory.entries {
+                    let child_hash = child
+                        .hash
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| "directory entry has a malformed hash")?;
+                    let path = entry_path(&output, &child.name)?;
+                    queue.push_back((child_hash, path, Some(child.clone())));
+                }
+                println!("Created directory {}", output.display());
+            }
+            None => {
+                fetch_file(&scheduler, &metadata, &file_hash, &output, window).await?;
+                if let Some(entry) = entry {
+                    set_executable(&output, entry.mode)?;
+                }
+            }
+        }
+    }
 
-        let metadata_response = provider_client
-            .get_file_metadata(Request::new(GetFileMetadataRequest {
-                file_hash: file_hash.to_vec(),
-            }))
-            .await?
-            .into_inner();
+    Ok(())
+}
 
-        let metadata: FileInfo = bincode::deserialize(&metadata_response.metadata)?;
+/// Downloads the chunks of one file to `output`.
+async fn fetch_file(
+    scheduler: &Scheduler,
+    metadata: &FileInfo,
+    file_hash: &[u8; 32],
+    output: &Path,
+    window: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut partial = PartialDownload::open(output, file_hash)?;
+    if partial.chunks_written() > 0 {
+        println!(
+            "Resuming download, {} chunks already fetched.",
+            partial.chunks_written()
+        );
+    }
+    let done = partial.written_indices();
+    // where the next chunk goes in the output
+    let mut offset = 0;
 
-        let mut file = fs::File::create(output)?;
+    let shard_size = match &metadata.stripes {
+        None => {
+            // batches arrive in order, at most `window` chunks held at once
+            let mut batches = scheduler
+                .chunk_batches(metadata, BATCH_SIZE)
+                .map(|batch| {
+                    let done = &done;
+                    async move {
+                        let (first, chunk_hashes) = batch?;
+                        let wanted: Vec<&[u8]> = (first..)
+                            .zip(&chunk_hashes)
+                            .filter(|(index, _)| !done.contains(index))
+                            .map(|(_, chunk_hash)| chunk_hash.as_slice())
+                            .collect();
+                        let fetched = if wanted.is_empty() {
+                            Vec::new()
+                        } else {
+                            scheduler.fetch_chunks(&wanted).await
+                        };
+                        Ok::<_, String>((first, chunk_hashes, fetched))
+                    }
+                })
+                .buffered(window.div_ceil(BATCH_SIZE).max(1));
+            while let Some(batch) = batches.next().await {
+                let (first, chunk_hashes, fetched) = batch?;
+                let mut fetched = fetched.into_iter();
+                for (index, chunk_hash) in (first..).zip(&chunk_hashes) {
+                    if done.contains(&index) {
+                        let (start, len) = partial.written(index).unwrap();
+                        offset = start + len;
+                        continue;
+                    }
+                    let chunk_data = fetched.next().flatten().ok_or_else(|| {
+                        format!("no provider has chunk {}", hex::encode(chunk_hash))
+                    })?;
+                    partial.write(index, offset, &chunk_data, chunk_hash)?;
+                    offset += chunk_data.len() as u64;
+                }
+            }
+            None
+        }
+        Some(layout) => {
+            let params = ErasureParams {
+                data_shards: layout.data_shards as usize,
+                parity_shards: layout.parity_shards as usize,
+            };
+            let stripe_len = params.data_shards + params.parity_shards;
 
-        for chunk_hash in metadata.chunk_hashes {
-            let chunk_response = provider_client
-                .get_chunk(Request::new(GetChunkRequest { chunk_hash }))
-                .await?
-                .into_inner();
-            file.write_all(&chunk_response.chunk_data)?;
+            // enough stripes in flight to keep the window busy
+            let mut stripes = scheduler
+                .chunk_batches(metadata, stripe_len)
+                .map(|stripe| {
+                    let done = &done;
+                    async move {
+                        let (first, stripe) = stripe?;
+                        if (first..first + params.data_shards).all(|i| done.contains(&i)) {
+                            return Ok((first, stripe, None));
+                        }
+                        let shards =
+                            fetch_stripe(scheduler, first / stripe_len, &stripe, params).await?;
+                        Ok::<_, String>((first, stripe, Some(shards)))
+                    }
+                })
+                .buffered(window.div_ceil(params.data_shards).max(1));
+            while let Some(stripe) = stripes.next().await {
+                let (first, stripe, fetched) = stripe?;
+                let Some(shards) = fetched else {
+                    let (start, len) = partial.written(first + params.data_shards - 1).unwrap();
+                    offset = start + len;
+                    continue;
+                };
+                for ((i, shard), shard_hash) in (first..).zip(shards).zip(&stripe) {
+                    let len = shard.len().min((metadata.size - offset) as usize);
+                    partial.write(i, offset, &shard[..len], shard_hash)?;
+                    offset += len as u64;
+                }
+            }
+            Some(layout.shard_size)
         }
-        println!("File downloaded successfully.");
-    } else {
-        println!("File not found on the network.");
+    };
+    partial.finish(file_hash, metadata.size, shard_size)?;
+    println!("File downloaded successfully.");
+    scheduler.print_summary();
+
+    Ok(())
+}
+
+/// Resolves a directory entry's name below `dir`, refusing names that are
+/// not a single path component so a listing can't write outside of it.
+fn entry_path(dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(dir.join(name)),
+        _ => Err(format!("invalid directory entry name {:?}", name)),
+    }
+}
+
+/// Adds the executable bits of `mode` to a downloaded file.
+fn set_executable(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_mode(permissions.mode() | (mode & 0o111));
+        std::fs::set_permissions(path, permissions)?;
     }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
 
+/// Writes part of a file to stdout. The node reads the range, fetching
+/// whatever it doesn't hold from the network.
+async fn cat_file(
+    node_addr: &str,
+    hash_str: &str,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = PeerServiceClient::connect(node_addr.to_string()).await?;
+    let mut pieces = client
+        .read_range(Request::new(ReadRangeRequest {
+            file_hash: hex::decode(hash_str)?,
+            offset,
+            length: length.unwrap_or(u64::MAX),
+        }))
+        .await?
+        .into_inner();
+    let mut stdout = std::io::stdout();
+    while let Some(piece) = pieces.message().await? {
+        stdout.write_all(&piece.data)?;
+    }
+    stdout.flush()?;
     Ok(())
 }
+
+/// Fetches the data shards of one stripe, using parity shards to rebuild the
+/// ones that can't be fetched.
+async fn fetch_stripe(
+    scheduler: &Scheduler,
+    index: usize,
+    stripe: &[Vec<u8>],
+    params: ErasureParams,
+) -> Result<Vec<Vec<u8>>, String> {
+    // data shards first; parity only as far as needed to make up for
+    // missing ones
+    let mut shards: Vec<Option<Vec<u8>>> = vec![None; stripe.len()];
+    let mut next = 0;
+    let mut available = 0;
+    while available < params.data_shards && next < stripe.len() {
+        let wanted = (params.data_shards - available).min(stripe.len() - next);
+        let shard_hashes: Vec<&[u8]> = stripe[next..next + wanted]
+            .iter()
+            .map(Vec::as_slice)
+            .collect();
+        let fetched = scheduler.fetch_chunks(&shard_hashes).await;
+        for (i, shard) in (next..).zip(fetched) {
+            available += shard.is_some() as usize;
+            shards[i] = shard;
+        }
+        next += wanted;
+    }
+    if available < params.data_shards {
+        return Err(format!(
+            "stripe {} has only {} of the {} shards needed",
+            index, available, params.data_shards
+        ));
+    }
+    if shards[..params.data_shards].iter().any(Option::is_none) {
+        println!("Reconstructing missing data shards of stripe {}", index);
+        erasure::reconstruct(&mut shards, params).map_err(|e| e.to_string())?;
+    }
+    Ok(shards
+        .into_iter()
+        .take(params.data_shards)
+        .flatten()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_names_stay_inside_the_directory() {
+        let dir = Path::new("out");
+        assert_eq!(entry_path(dir, "a.txt").unwrap(), dir.join("a.txt"));
+        for name in ["", ".", "..", "../x", "a/b", "/etc/passwd"] {
+            assert!(entry_path(dir, name).is_err(), "{:?}", name);
+        }
+    }
+}
@@ -1,75 +1,219 @@
-use crate::storage_proto::{peer_service_client::PeerServiceClient, PeerMessage, PingRequest};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
-
-pub async fn ping_peer(peer: Peer) -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = PeerServiceClient::connect(peer.address.clone()).await?;
-    let request = tonic::Request::new(PingRequest {
-        peer: Some(PeerMessage {
-            node_id: peer.node_id.to_vec(),
-            address: peer.address,
-        }),
-    });
-    client.ping(request).await?;
-    Ok(())
-}
+use std::time::{Duration, Instant};
 
 pub const K_VALUE: usize = 20;
+// how many candidates each bucket remembers for when a peer goes away
+pub const REPLACEMENT_CACHE_SIZE: usize = K_VALUE;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Peer {
     pub node_id: [u8; 32],
     pub address: String,
+    pub public_key: [u8; 32],
+}
+
+/// A k-bucket: up to `K_VALUE` live peers, plus a cache of candidates that
+/// take over when one of them turns out to be dead.
+pub struct KBucket {
+    // most recently seen at the front, least recently seen at the back
+    pub peers: VecDeque<Peer>,
+    pub replacements: VecDeque<Peer>,
+    // when a lookup last targeted an ID in this bucket's range
+    pub last_refreshed: Instant,
+}
+
+impl Default for KBucket {
+    fn default() -> Self {
+        Self {
+            peers: VecDeque::new(),
+            replacements: VecDeque::new(),
+            last_refreshed: Instant::now(),
+        }
+    }
+}
+
+impl KBucket {
+    fn position(&self, node_id: &[u8; 32]) -> Option<usize> {
+        self.peers.iter().position(|p| &p.node_id == node_id)
+    }
+
+    /// Moves a known peer to the head of the bucket.
+    fn touch(&mut self, node_id: &[u8; 32]) -> bool {
+        match self.position(node_id) {
+            Some(pos) => {
+                let p = self.peers.remove(pos).unwrap();
+                self.peers.push_front(p);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers a peer that didn't fit, dropping the oldest candidate if the
+    /// cache is full.
+    fn add_replacement(&mut self, peer: Peer) {
+        self.replacements.retain(|p| p.node_id != peer.node_id);
+        self.replacements.push_front(peer);
+        self.replacements.truncate(REPLACEMENT_CACHE_SIZE);
+    }
+
+    /// Removes a peer and promotes the most recently seen replacement.
+    fn evict(&mut self, node_id: &[u8; 32]) -> bool {
+        let Some(pos) = self.position(node_id) else {
+            return false;
+        };
+        self.peers.remove(pos);
+        if let Some(replacement) = self.replacements.pop_front() {
+            self.peers.push_front(replacement);
+        }
+        true
+    }
 }
 
 pub struct RoutingTable {
     pub local_node_id: [u8; 32],
-    pub buckets: [VecDeque<Peer>; 256],
+    pub buckets: [KBucket; 256],
+    // least recently seen peers of full buckets, waiting for a liveness check
+    pending_checks: VecDeque<Peer>,
 }
 
 impl RoutingTable {
     pub fn new(local_node_id: [u8; 32]) -> Self {
         Self {
             local_node_id,
-            buckets: std::array::from_fn(|_| VecDeque::with_capacity(K_VALUE)),
+            buckets: std::array::from_fn(|_| KBucket::default()),
+            pending_checks: VecDeque::new(),
         }
     }
 
-    pub async fn add_peer(&mut self, peer: Peer) {
+    /// Records that we heard from `peer`.
+    ///
+    /// Known peers move to the head of their bucket and new ones are added if
+    /// there is room. When the bucket is full the newcomer goes to the
+    /// replacement cache and the least recently seen peer is queued for a
+    /// liveness check, which happens outside the table (see
+    /// `take_pending_checks`). Returns `true` if a new check was queued.
+    pub fn add_peer(&mut self, peer: Peer) -> bool {
         //  check if this is own node_id , lol
         if self.local_node_id == peer.node_id {
-            return;
+            return false;
         }
 
         let bucket_index = self.bucket_index(&peer.node_id);
         let bucket = &mut self.buckets[bucket_index];
 
-        if let Some(pos) = bucket.iter().position(|p| p.node_id == peer.node_id) {
-            // Move the existing peer to the front
-            let p = bucket.remove(pos).unwrap();
-            bucket.push_front(p);
-        } else if bucket.len() < K_VALUE {
-            bucket.push_front(peer);
-        } else {
-            if let Some(last_peer) = bucket.pop_front() {
-                match ping_peer(last_peer).await {
-                    Ok(_) => {
-                        println!("the bucket is full");
-                    }
-                    Err(_) => {
-                        println!("adding new peer");
-                        bucket.push_front(peer);
-                    }
-                }
-            }
+        if bucket.touch(&peer.node_id) {
+            return false;
+        }
+        if bucket.peers.len() < K_VALUE {
+            bucket.peers.push_front(peer);
+            return false;
         }
+
+        log::debug!(
+            "Bucket {} is full, caching {} as a replacement",
+            bucket_index,
+            peer.address
+        );
+        bucket.add_replacement(peer);
+
+        let least_recent = bucket.peers.back().cloned().unwrap();
+        self.queue_check(least_recent)
     }
 
-    pub fn find_closest_peers(&self, target_id: &[u8; 32]) -> Vec<Peer> {
-        let mut peers: Vec<(u128, Peer)> = self
-            .buckets
+    /// Queues a liveness check for `peer` unless one is already pending.
+    pub fn queue_check(&mut self, peer: Peer) -> bool {
+        if self
+            .pending_checks
             .iter()
-            .flat_map(|bucket| bucket.iter().cloned())
+            .any(|p| p.node_id == peer.node_id)
+        {
+            return false;
+        }
+        self.pending_checks.push_back(peer);
+        true
+    }
+
+    /// Hands out the peers waiting for a liveness check. The caller pings them
+    /// without holding the table and reports back with `mark_alive` or
+    /// `remove_peer`.
+    pub fn take_pending_checks(&mut self) -> Vec<Peer> {
+        self.pending_checks.drain(..).collect()
+    }
+
+    /// Moves a peer that answered a liveness check back to the head of its
+    /// bucket.
+    pub fn mark_alive(&mut self, node_id: &[u8; 32]) {
+        let bucket_index = self.bucket_index(node_id);
+        self.buckets[bucket_index].touch(node_id);
+    }
+
+    /// Drops a peer that failed to respond, promoting a replacement if one is
+    /// cached for its bucket.
+    pub fn remove_peer(&mut self, node_id: &[u8; 32]) -> bool {
+        let bucket_index = self.bucket_index(node_id);
+        let removed = self.buckets[bucket_index].evict(node_id);
+        if removed {
+            log::info!("Removed unresponsive peer {}", hex::encode(node_id));
+        }
+        removed
+    }
+
+    /// Records that a lookup for `target` just refreshed its bucket.
+    pub fn mark_refreshed(&mut self, target: &[u8; 32]) {
+        let bucket_index = self.bucket_index(target);
+        self.buckets[bucket_index].last_refreshed = Instant::now();
+    }
+
+    /// Indices of the non-empty buckets no lookup has refreshed for `max_age`.
+    pub fn stale_buckets(&self, max_age: Duration) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !b.peers.is_empty() && b.last_refreshed.elapsed() >= max_age)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// A random ID that falls into bucket `index`, i.e. one sharing exactly
+    /// `255 - index` leading bits with our own ID.
+    pub fn random_id_in_bucket(&self, index: usize) -> [u8; 32] {
+        let prefix_len = 255 - index;
+        let mut id: [u8; 32] = rand::rng().random();
+        for bit in 0..=prefix_len {
+            let (byte, mask) = (bit / 8, 0x80u8 >> (bit % 8));
+            let local = self.local_node_id[byte] & mask;
+            // copy our prefix, then flip the first bit after it
+            let value = if bit == prefix_len {
+                local ^ mask
+            } else {
+                local
+            };
+            id[byte] = (id[byte] & !mask) | value;
+        }
+        id
+    }
+
+    /// The peer in bucket `index` we heard from least recently.
+    pub fn least_recently_seen(&self, index: usize) -> Option<Peer> {
+        self.buckets[index].peers.back().cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|b| b.peers.is_empty())
+    }
+
+    /// Every peer currently in the table.
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.buckets.iter().flat_map(|bucket| bucket.peers.iter())
+    }
+
+    pub fn find_closest_peers(&self, target_id: &[u8; 32]) -> Vec<Peer> {
+        let mut peers: Vec<(Distance, Peer)> = self
+            .peers()
+            .cloned()
             .map(|peer| (xor_distance(&peer.node_id, target_id), peer))
             .collect();
 
@@ -83,7 +227,7 @@ impl RoutingTable {
 
     fn bucket_index(&self, node_id: &[u8; 32]) -> usize {
         let distance = xor_distance(&self.local_node_id, node_id);
-        if distance == 0 {
+        if distance.is_zero() {
             return 0;
         }
         // log2 distance ( typical kademelia style )
@@ -91,10 +235,291 @@ impl RoutingTable {
     }
 }
 
-pub fn xor_distance(id1: &[u8; 32], id2: &[u8; 32]) -> u128 {
-    let mut dist = [0u8; 16];
-    for i in 0..16 {
-        dist[i] = id1[i] ^ id2[i];
+/// The XOR distance between two node IDs, ordered as a 256-bit big-endian
+/// unsigned integer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Distance([u8; 32]);
+
+impl Distance {
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+
+    /// Number of leading zero bits, i.e. the length of the common prefix of
+    /// the two IDs. A zero distance has 256 leading zeros.
+    pub fn leading_zeros(&self) -> u32 {
+        match self.0.iter().position(|b| *b != 0) {
+            Some(i) => i as u32 * 8 + self.0[i].leading_zeros(),
+            None => 256,
+        }
+    }
+}
+
+pub fn xor_distance(id1: &[u8; 32], id2: &[u8; 32]) -> Distance {
+    Distance(std::array::from_fn(|i| id1[i] ^ id2[i]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    const ROUNDS: usize = 1000;
+
+    fn random_id(rng: &mut impl Rng) -> [u8; 32] {
+        rng.random()
+    }
+
+    /// Returns an ID sharing exactly `prefix_len` leading bits with `id`.
+    fn id_with_common_prefix(rng: &mut impl Rng, id: &[u8; 32], prefix_len: usize) -> [u8; 32] {
+        let mut other = random_id(rng);
+        for bit in 0..=prefix_len {
+            let (byte, mask) = (bit / 8, 0x80u8 >> (bit % 8));
+            let same = id[byte] & mask;
+            // copy the shared prefix, then flip the first differing bit
+            let value = if bit == prefix_len { same ^ mask } else { same };
+            other[byte] = (other[byte] & !mask) | value;
+        }
+        other
+    }
+
+    fn peer(node_id: [u8; 32]) -> Peer {
+        Peer {
+            node_id,
+            address: format!("http://{}", hex::encode(&node_id[..4])),
+            public_key: [0; 32],
+        }
+    }
+
+    #[test]
+    fn distance_to_self_is_zero() {
+        let mut rng = rand::rng();
+        for _ in 0..ROUNDS {
+            let id = random_id(&mut rng);
+            let distance = xor_distance(&id, &id);
+            assert!(distance.is_zero());
+            assert_eq!(distance.leading_zeros(), 256);
+        }
+    }
+
+    #[test]
+    fn distance_is_symmetric() {
+        let mut rng = rand::rng();
+        for _ in 0..ROUNDS {
+            let (a, b) = (random_id(&mut rng), random_id(&mut rng));
+            assert_eq!(xor_distance(&a, &b), xor_distance(&b, &a));
+        }
+    }
+
+    #[test]
+    fn leading_zeros_matches_common_prefix() {
+        let mut rng = rand::rng();
+        for prefix_len in 0..256 {
+            let id = random_id(&mut rng);
+            let other = id_with_common_prefix(&mut rng, &id, prefix_len);
+            assert_eq!(xor_distance(&id, &other).leading_zeros(), prefix_len as u32);
+        }
+    }
+
+    #[test]
+    fn ordering_matches_256_bit_integers() {
+        let mut rng = rand::rng();
+        for _ in 0..ROUNDS {
+            let target = random_id(&mut rng);
+            let (a, b) = (random_id(&mut rng), random_id(&mut rng));
+            let (da, db) = (xor_distance(&a, &target), xor_distance(&b, &target));
+
+            // compare as (high, low) pairs of u128s
+            let split = |d: &Distance| {
+                (
+                    u128::from_be_bytes(d.0[..16].try_into().unwrap()),
+                    u128::from_be_bytes(d.0[16..].try_into().unwrap()),
+                )
+            };
+            assert_eq!(da.cmp(&db), split(&da).cmp(&split(&db)));
+        }
+    }
+
+    #[test]
+    fn ids_sharing_a_128_bit_prefix_are_distinguished() {
+        let mut rng = rand::rng();
+        for prefix_len in 128..256 {
+            let id = random_id(&mut rng);
+            let other = id_with_common_prefix(&mut rng, &id, prefix_len);
+            assert!(!xor_distance(&id, &other).is_zero());
+        }
+    }
+
+    #[test]
+    fn bucket_index_covers_the_full_id_space() {
+        let mut rng = rand::rng();
+        let table = RoutingTable::new(random_id(&mut rng));
+        for prefix_len in 0..256 {
+            for _ in 0..8 {
+                let other = id_with_common_prefix(&mut rng, &table.local_node_id, prefix_len);
+                assert_eq!(table.bucket_index(&other), 255 - prefix_len);
+            }
+        }
+    }
+
+    #[test]
+    fn find_closest_peers_returns_the_k_closest_in_order() {
+        let mut rng = rand::rng();
+        for _ in 0..20 {
+            let mut table = RoutingTable::new(random_id(&mut rng));
+            let mut all = Vec::new();
+            for _ in 0..200 {
+                // spread peers over many buckets, including very deep ones
+                let prefix_len = rng.random_range(0..256);
+                let p = peer(id_with_common_prefix(
+                    &mut rng,
+                    &table.local_node_id,
+                    prefix_len,
+                ));
+                let index = table.bucket_index(&p.node_id);
+                table.buckets[index].peers.push_front(p.clone());
+                all.push(p);
+            }
+
+            let target = random_id(&mut rng);
+            let closest = table.find_closest_peers(&target);
+
+            all.sort_by_key(|p| xor_distance(&p.node_id, &target));
+            assert_eq!(closest.len(), K_VALUE);
+            assert_eq!(closest, all[..K_VALUE].to_vec());
+        }
+    }
+
+    fn fill_bucket(table: &mut RoutingTable, rng: &mut impl Rng) -> Vec<Peer> {
+        (0..K_VALUE)
+            .map(|_| {
+                // every ID differing in the first bit lands in bucket 255
+                let p = peer(id_with_common_prefix(rng, &table.local_node_id, 0));
+                table.buckets[255].peers.push_front(p.clone());
+                p
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_bucket_queues_least_recent_peer_for_a_check() {
+        let mut rng = rand::rng();
+        let mut table = RoutingTable::new(random_id(&mut rng));
+        let peers = fill_bucket(&mut table, &mut rng);
+
+        let newcomer = peer(id_with_common_prefix(&mut rng, &table.local_node_id, 0));
+        assert!(table.add_peer(newcomer.clone()));
+        // a second newcomer doesn't queue the same peer twice
+        let other = peer(id_with_common_prefix(&mut rng, &table.local_node_id, 0));
+        assert!(!table.add_peer(other));
+
+        assert_eq!(table.take_pending_checks(), vec![peers[0].clone()]);
+        assert!(table.take_pending_checks().is_empty());
+        assert_eq!(table.buckets[255].peers.len(), K_VALUE);
+        assert_eq!(table.buckets[255].replacements.len(), 2);
+    }
+
+    #[test]
+    fn live_least_recent_peer_is_kept() {
+        let mut rng = rand::rng();
+        let mut table = RoutingTable::new(random_id(&mut rng));
+        let peers = fill_bucket(&mut table, &mut rng);
+
+        let newcomer = peer(id_with_common_prefix(&mut rng, &table.local_node_id, 0));
+        table.add_peer(newcomer.clone());
+        table.take_pending_checks();
+        table.mark_alive(&peers[0].node_id);
+
+        let bucket = &table.buckets[255];
+        assert_eq!(bucket.peers.front(), Some(&peers[0]));
+        assert!(bucket.position(&newcomer.node_id).is_none());
+        assert_eq!(bucket.replacements.front(), Some(&newcomer));
+    }
+
+    #[test]
+    fn dead_least_recent_peer_is_replaced_by_newcomer() {
+        let mut rng = rand::rng();
+        let mut table = RoutingTable::new(random_id(&mut rng));
+        let peers = fill_bucket(&mut table, &mut rng);
+
+        let newcomer = peer(id_with_common_prefix(&mut rng, &table.local_node_id, 0));
+        table.add_peer(newcomer.clone());
+        table.take_pending_checks();
+        assert!(table.remove_peer(&peers[0].node_id));
+
+        let bucket = &table.buckets[255];
+        assert_eq!(bucket.peers.len(), K_VALUE);
+        assert_eq!(bucket.peers.front(), Some(&newcomer));
+        assert!(bucket.position(&peers[0].node_id).is_none());
+        assert!(bucket.replacements.is_empty());
+    }
+
+    #[test]
+    fn known_peer_moves_to_head() {
+        let mut rng = rand::rng();
+        let mut table = RoutingTable::new(random_id(&mut rng));
+        let peers = fill_bucket(&mut table, &mut rng);
+
+        assert!(table.buckets[255].touch(&peers[0].node_id));
+        assert_eq!(table.buckets[255].peers.front(), Some(&peers[0]));
+        assert_eq!(table.buckets[255].peers.back(), Some(&peers[1]));
+    }
+
+    #[test]
+    fn removing_a_peer_promotes_the_newest_replacement() {
+        let mut rng = rand::rng();
+        let mut table = RoutingTable::new(random_id(&mut rng));
+        let peers = fill_bucket(&mut table, &mut rng);
+
+        let candidates: Vec<Peer> = (0..3)
+            .map(|_| peer(id_with_common_prefix(&mut rng, &table.local_node_id, 0)))
+            .collect();
+        for candidate in &candidates {
+            table.buckets[255].add_replacement(candidate.clone());
+        }
+
+        assert!(table.remove_peer(&peers[5].node_id));
+        let bucket = &table.buckets[255];
+        assert_eq!(bucket.peers.len(), K_VALUE);
+        assert_eq!(bucket.peers.front(), Some(&candidates[2]));
+        assert_eq!(bucket.replacements.len(), 2);
+        assert!(!table.remove_peer(&peers[5].node_id));
+    }
+
+    #[test]
+    fn replacement_cache_is_bounded() {
+        let mut rng = rand::rng();
+        let mut bucket = KBucket::default();
+        for _ in 0..REPLACEMENT_CACHE_SIZE * 2 {
+            bucket.add_replacement(peer(random_id(&mut rng)));
+        }
+        assert_eq!(bucket.replacements.len(), REPLACEMENT_CACHE_SIZE);
+    }
+
+    #[test]
+    fn random_id_in_bucket_lands_in_that_bucket() {
+        let mut rng = rand::rng();
+        let table = RoutingTable::new(random_id(&mut rng));
+        for index in 0..256 {
+            let id = table.random_id_in_bucket(index);
+            assert_eq!(table.bucket_index(&id), index);
+        }
+    }
+
+    #[test]
+    fn only_untouched_non_empty_buckets_are_stale() {
+        let mut rng = rand::rng();
+        let mut table = RoutingTable::new(random_id(&mut rng));
+        let peers = fill_bucket(&mut table, &mut rng);
+        let close = peer(id_with_common_prefix(&mut rng, &table.local_node_id, 200));
+        table.add_peer(close.clone());
+
+        assert!(table.stale_buckets(Duration::from_secs(60)).is_empty());
+        assert_eq!(table.stale_buckets(Duration::ZERO), vec![55, 255]);
+
+        table.buckets[255].last_refreshed -= Duration::from_secs(120);
+        table.buckets[55].last_refreshed -= Duration::from_secs(120);
+        table.mark_refreshed(&peers[3].node_id);
+        assert_eq!(table.stale_buckets(Duration::from_secs(60)), vec![55]);
     }
-    u128::from_be_bytes(dist)
 }
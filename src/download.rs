@@ -0,0 +1,698 @@
+use crate::manifest::ManifestWalker;
+use crate::storage_proto::peer_service_client::PeerServiceClient;
+use crate::storage_proto::{
+    ChunkStatus, FileInfo, FindProvidersRequest, GetChunkRequest, GetChunksRequest,
+    GetFileMetadataRequest, ProviderRecord,
+};
+use crate::utils::hash;
+use futures::future::join_all;
+use futures::stream::{self, Stream};
+use rand::seq::IndexedRandom;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Request;
+
+/// How many consecutive chunks are requested from a provider over one
+/// GetChunks stream.
+pub const BATCH_SIZE: usize = 8;
+
+// how long a provider gets to serve one chunk before we ask another
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+// consecutive failures after which a provider is no longer asked
+const MAX_FAILURES: u32 = 3;
+// weight of the latest sample in a provider's smoothed throughput
+const SMOOTHING: f64 = 0.3;
+
+struct ProviderState {
+    address: String,
+    client: PeerServiceClient<Channel>,
+    // smoothed bytes per second, once a chunk has been fetched
+    throughput: Option<f64>,
+    in_flight: usize,
+    failures: u32,
+    chunks: u64,
+    bytes: u64,
+}
+
+/// Fetches a file's chunks from all of its providers at once.
+///
+/// At most `window` chunks are requested at a time, in batches streamed
+/// over one GetChunks call each so a provider isn't idle between chunks
+/// waiting for the next request. Each batch goes to a
+/// provider picked at random, weighted by the throughput it has shown so far
+/// and by how busy it already is, so fast providers serve most of the file
+/// without the slow ones sitting idle. A chunk that fails or comes back
+/// corrupt is retried on the other providers, then on the chunk's own
+/// replicas.
+pub struct Scheduler {
+    providers: Mutex<Vec<ProviderState>>,
+    node: PeerServiceClient<Channel>,
+    window: Semaphore,
+    window_size: usize,
+}
+
+impl Scheduler {
+    pub fn new(
+        providers: &[ProviderRecord],
+        node: PeerServiceClient<Channel>,
+        window: usize,
+    ) -> Self {
+        let providers = providers
+            .iter()
+            .filter_map(|provider| {
+                // connections are made on first use, so idle providers cost
+                // nothing
+                let channel = Endpoint::from_shared(provider.address.clone())
+                    .ok()?
+                    .connect_lazy();
+                Some(ProviderState {
+                    address: provider.address.clone(),
+                    client: PeerServiceClient::new(channel),
+                    throughput: None,
+                    in_flight: 0,
+                    failures: 0,
+                    chunks: 0,
+                    bytes: 0,
+                })
+            })
+            .collect();
+        Self {
+            providers: Mutex::new(providers),
+            node,
+            window: Semaphore::new(window.max(1)),
+            window_size: window.max(1),
+        }
+    }
+
+    /// Fetches and verifies the file metadata, trying providers in order.
+    pub async fn fetch_metadata(
+        &self,
+        file_hash: &[u8],
+    ) -> Result<FileInfo, Box<dyn std::error::Error>> {
+        let providers: Vec<_> = {
+            let providers = self.providers.lock().unwrap();
+            providers
+                .iter()
+                .map(|p| (p.address.clone(), p.client.clone()))
+                .collect()
+        };
+        for (address, mut client) in providers {
+            let result = async {
+                let response = client
+                    .get_file_metadata(Request::new(GetFileMetadataRequest {
+                        file_hash: file_hash.to_vec(),
+                    }))
+                    .await?
+                    .into_inner();
+                if hash(&response.metadata) != file_hash {
+                    return Err("metadata does not match the file hash".into());
+                }
+                let metadata = crate::storage::FileInfo::decode(&response.metadata)?;
+                Ok::<_, Box<dyn std::error::Error>>(FileInfo::from(metadata))
+            }
+            .await;
+            match result {
+                Ok(info) => {
+                    println!("Fetched metadata from {}", address);
+                    return Ok(info);
+                }
+                Err(e) => println!("Provider {} failed: {}", address, e),
+            }
+        }
+        Err("no provider could serve the file metadata".into())
+    }
+
+    /// Streams a file's chunk hashes in batches of `batch_len`, each with the
+    /// index of its first chunk. Manifest nodes are fetched, and verified
+    /// like any chunk, only once the batches reach the chunks under them.
+    pub fn chunk_batches<'a>(
+        &'a self,
+        metadata: &FileInfo,
+        batch_len: usize,
+    ) -> impl Stream<Item = Result<(usize, Vec<Vec<u8>>), String>> + Unpin + 'a {
+        let walker = ManifestWalker::new(metadata.chunk_hashes.clone(), metadata.manifest_depth);
+        Box::pin(stream::unfold(Some((walker, 0)), move |state| async move {
+            let (mut walker, first) = state?;
+            let mut batch = Vec::with_capacity(batch_len);
+            while batch.len() < batch_len {
+                let load = |hash: Vec<u8>| async move { self.fetch_chunk(&hash).await };
+                match walker.next(load).await {
+                    Ok(Some(chunk_hash)) => batch.push(chunk_hash),
+                    Ok(None) => break,
+                    // end the stream after the error
+                    Err(e) => return Some((Err(e), None)),
+                }
+            }
+            if batch.is_empty() {
+                return None;
+            }
+            let next = first + batch.len();
+            Some((Ok((first, batch)), Some((walker, next))))
+        }))
+    }
+
+    /// Fetches a batch of chunks from one provider over a single stream.
+    /// Whatever it fails to deliver intact is then fetched chunk by chunk,
+    /// with failover. Returns `None` for chunks nobody has an intact copy of.
+    pub async fn fetch_chunks(&self, chunk_hashes: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let mut chunks = vec![None; chunk_hashes.len()];
+        let permits = chunk_hashes.len().clamp(1, self.window_size) as u32;
+        let permits = self.window.acquire_many(permits).await;
+        if let Some((i, client)) = self.pick(&vec![false; self.provider_count()]) {
+            let started = Instant::now();
+            let result = stream_chunks(client, chunk_hashes, &mut chunks).await;
+            let bytes: usize = chunks.iter().flatten().map(Vec::len).sum();
+            match result {
+                Ok(()) if chunks.iter().all(Option::is_some) => {
+                    self.record_success(i, chunks.len(), bytes, started.elapsed());
+                }
+                result => {
+                    let address = self.record_failure(i);
+                    let reason = match result {
+                        Ok(()) => "some chunks were missing or corrupt".to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                    println!("Provider {} failed to stream chunks: {}", address, reason);
+                }
+            }
+        }
+        // retries take permits of their own
+        drop(permits);
+
+        let retries = chunks
+            .iter_mut()
+            .zip(chunk_hashes)
+            .filter(|(chunk, _)| chunk.is_none())
+            .map(|(chunk, chunk_hash)| async move { *chunk = self.fetch_chunk(chunk_hash).await });
+        join_all(retries).await;
+        chunks
+    }
+
+    /// Fetches a chunk, failing over to the other providers and then to the
+    /// chunk's replicas. Returns `None` if nobody has an intact copy.
+    pub async fn fetch_chunk(&self, chunk_hash: &[u8]) -> Option<Vec<u8>> {
+        let _permit = self.window.acquire().await.ok()?;
+
+        let mut tried = vec![false; self.provider_count()];
+        while let Some((i, mut client)) = self.pick(&tried) {
+            tried[i] = true;
+            let started = Instant::now();
+            let request = client.get_chunk(Request::new(GetChunkRequest {
+                chunk_hash: chunk_hash.to_vec(),
+            }));
+            let result = match tokio::time::timeout(CHUNK_TIMEOUT, request).await {
+                Ok(Ok(response)) => verified_chunk(chunk_hash, response.into_inner().chunk_data),
+                Ok(Err(status)) => Err(status.into()),
+                Err(elapsed) => Err(elapsed.into()),
+            };
+            match result {
+                Ok(data) => {
+                    self.record_success(i, 1, data.len(), started.elapsed());
+                    return Some(data);
+                }
+                Err(e) => {
+                    let address = self.record_failure(i);
+                    println!(
+                        "Provider {} failed to serve chunk {}: {}",
+                        address,
+                        hex::encode(chunk_hash),
+                        e
+                    );
+                }
+            }
+        }
+        self.fetch_chunk_replica(chunk_hash).await
+    }
+
+    fn provider_count(&self) -> usize {
+        self.providers.lock().unwrap().len()
+    }
+
+    /// Picks an untried, healthy provider for the next request and counts the
+    /// request against it.
+    fn pick(&self, tried: &[bool]) -> Option<(usize, PeerServiceClient<Channel>)> {
+        let mut providers = self.providers.lock().unwrap();
+
+        // providers we haven't measured yet are assumed to be average, so
+        // they get their share of requests until we know better
+        let measured: Vec<f64> = providers.iter().filter_map(|p| p.throughput).collect();
+        let average = if measured.is_empty() {
+            1.0
+        } else {
+            measured.iter().sum::<f64>() / measured.len() as f64
+        };
+
+        let candidates: Vec<(usize, f64)> = providers
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| !tried[*i] && p.failures < MAX_FAILURES)
+            .map(|(i, p)| {
+                let throughput = p.throughput.unwrap_or(average).max(f64::MIN_POSITIVE);
+                (i, throughput / (p.in_flight + 1) as f64)
+            })
+            .collect();
+        let (i, _) = *candidates
+            .choose_weighted(&mut rand::rng(), |(_, weight)| *weight)
+            .ok()?;
+
+        let provider = &mut providers[i];
+        provider.in_flight += 1;
+        Some((i, provider.client.clone()))
+    }
+
+    fn record_success(&self, i: usize, chunks: usize, len: usize, elapsed: Duration) {
+        let mut providers = self.providers.lock().unwrap();
+        let provider = &mut providers[i];
+        let sample = len as f64 / elapsed.as_secs_f64().max(1e-3);
+        provider.throughput = Some(match provider.throughput {
+            Some(throughput) => SMOOTHING * sample + (1.0 - SMOOTHING) * throughput,
+            None => sample,
+        });
+        provider.in_flight -= 1;
+        provider.failures = 0;
+        provider.chunks += chunks as u64;
+        provider.bytes += len as u64;
+    }
+
+    /// Counts a failed request against a provider and returns its address.
+    fn record_failure(&self, i: usize) -> String {
+        let mut providers = self.providers.lock().unwrap();
+        let provider = &mut providers[i];
+        provider.in_flight -= 1;
+        provider.failures += 1;
+        if provider.failures == MAX_FAILURES {
+            println!(
+                "Giving up on provider {} after {} failures in a row",
+                provider.address, MAX_FAILURES
+            );
+        }
+        provider.address.clone()
+    }
+
+    /// Looks up the providers of a single chunk and fetches it from the first
+    /// one that serves it.
+    async fn fetch_chunk_replica(&self, chunk_hash: &[u8]) -> Option<Vec<u8>> {
+        let providers = self
+            .node
+            .clone()
+            .find_providers(Request::new(FindProvidersRequest {
+                key: chunk_hash.to_vec(),
+            }))
+            .await
+            .ok()?
+            .into_inner()
+            .providers;
+
+        for provider in providers {
+            let result = async {
+                let mut replica = PeerServiceClient::connect(provider.address.clone()).await?;
+                let response = replica
+                    .get_chunk(Request::new(GetChunkRequest {
+                        chunk_hash: chunk_hash.to_vec(),
+                    }))
+                    .await?;
+                verified_chunk(chunk_hash, response.into_inner().chunk_data)
+            }
+            .await;
+            match result {
+                Ok(data) => return Some(data),
+                Err(e) => println!(
+                    "Replica {} failed to serve chunk {}: {}",
+                    provider.address,
+                    hex::encode(chunk_hash),
+                    e
+                ),
+            }
+        }
+        None
+    }
+
+    /// Prints how much each provider served and how fast.
+    pub fn print_summary(&self) {
+        let providers = self.providers.lock().unwrap();
+        for provider in providers.iter().filter(|p| p.chunks > 0) {
+            println!(
+                "- {}: {} chunks, {} KiB at {:.0} KiB/s",
+                provider.address,
+                provider.chunks,
+                provider.bytes / 1024,
+                provider.throughput.unwrap_or(0.0) / 1024.0
+            );
+        }
+    }
+}
+
+/// Reads the chunks of one GetChunks call into `chunks`, which lines up with
+/// `chunk_hashes`. Chunks the provider doesn't have or sends corrupted are
+/// left out.
+async fn stream_chunks(
+    mut client: PeerServiceClient<Channel>,
+    chunk_hashes: &[&[u8]],
+    chunks: &mut [Option<Vec<u8>>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = Request::new(GetChunksRequest {
+        chunk_hashes: chunk_hashes.iter().map(|h| h.to_vec()).collect(),
+        file_hash: Vec::new(),
+    });
+    let mut stream = tokio::time::timeout(CHUNK_TIMEOUT, client.get_chunks(request))
+        .await??
+        .into_inner();
+    for (chunk, chunk_hash) in chunks.iter_mut().zip(chunk_hashes) {
+        let Some(item) = tokio::time::timeout(CHUNK_TIMEOUT, stream.message()).await?? else {
+            return Err("stream ended early".into());
+        };
+        if item.chunk_hash != *chunk_hash {
+            return Err("chunks arrived out of order".into());
+        }
+        if item.status() == ChunkStatus::Ok {
+            *chunk = verified_chunk(chunk_hash, item.chunk_data).ok();
+        }
+    }
+    Ok(())
+}
+
+/// Passes chunk data through if it matches its hash, so a corrupt copy is
+/// treated like a failed provider and the chunk is fetched elsewhere.
+fn verified_chunk(chunk_hash: &[u8], data: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if hash(&data) != chunk_hash {
+        return Err("corrupt data, it does not match the chunk hash".into());
+    }
+    Ok(data)
+}
+
+/// A download in progress. Chunks are written to `<output>.part`, and the
+/// index, offset, length and hash of each one is appended to
+/// `<output>.part.state` once it is on disk, so a rerun after a failure only
+/// fetches what's missing. The output only appears, atomically, once every
+/// chunk checks out.
+pub struct PartialDownload {
+    output: PathBuf,
+    temp_path: PathBuf,
+    state_path: PathBuf,
+    file: fs::File,
+    state: fs::File,
+    written: BTreeMap<usize, WrittenChunk>,
+}
+
+/// Where a chunk was written, and the hash it was verified against.
+struct WrittenChunk {
+    offset: u64,
+    len: u64,
+    hash: Vec<u8>,
+}
+
+impl PartialDownload {
+    /// Picks up an earlier attempt at downloading `file_hash` to `output`, or
+    /// starts a new one.
+    pub fn open(output: &Path, file_hash: &[u8]) -> io::Result<Self> {
+        let temp_path = with_suffix(output, ".part");
+        let state_path = with_suffix(output, ".part.state");
+        let header = hex::encode(file_hash);
+
+        // the state only counts if it belongs to this file and the temp file
+        // is still there
+        let mut written = BTreeMap::new();
+        let resumable = match fs::File::open(&state_path) {
+            Ok(state) if temp_path.exists() => {
+                let mut lines = BufReader::new(state).lines();
+                if lines.next().transpose()?.as_deref() == Some(header.as_str()) {
+                    // a line cut short by a crash simply doesn't parse
+                    for line in lines {
+                        if let Some((index, chunk)) = parse_state_line(&line?) {
+                            written.insert(index, chunk);
+                        }
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            Ok(_) => false,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(!resumable)
+            .open(&temp_path)?;
+        // compact the state now, dropping any half-written last line
+        let state = write_state(&state_path, &header, &written)?;
+        Ok(Self {
+            output: output.to_path_buf(),
+            temp_path,
+            state_path,
+            file,
+            state,
+            written,
+        })
+    }
+
+    /// Where chunk `index` was written, as offset and length, if it already
+    /// was.
+    pub fn written(&self, index: usize) -> Option<(u64, u64)> {
+        self.written
+            .get(&index)
+            .map(|chunk| (chunk.offset, chunk.len))
+    }
+
+    pub fn chunks_written(&self) -> usize {
+        self.written.len()
+    }
+
+    pub fn written_indices(&self) -> HashSet<usize> {
+        self.written.keys().copied().collect()
+    }
+
+    /// Writes chunk `index`, verified against `chunk_hash`, at `offset` and
+    /// records it in the state file.
+    pub fn write(
+        &mut self,
+        index: usize,
+        offset: u64,
+        data: &[u8],
+        chunk_hash: &[u8],
+    ) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        let chunk = WrittenChunk {
+            offset,
+            len: data.len() as u64,
+            hash: chunk_hash.to_vec(),
+        };
+        writeln!(self.state, "{}", state_line(index, &chunk))?;
+        self.written.insert(index, chunk);
+        Ok(())
+    }
+
+    /// Checks every written chunk against its hash and moves the file into
+    /// place. Chunks that fail are forgotten, so the next run fetches them
+    /// again.
+    ///
+    /// Erasure-coded files only contain the data shards, without the padding
+    /// of the last ones; they are padded back to `shard_size` for hashing.
+    pub fn finish(
+        mut self,
+        file_hash: &[u8],
+        size: u64,
+        shard_size: Option<u64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut corrupt = Vec::new();
+        for (&index, chunk) in &self.written {
+            let mut data = vec![0; chunk.len as usize];
+            self.file.seek(SeekFrom::Start(chunk.offset))?;
+            self.file.read_exact(&mut data)?;
+            if let Some(shard_size) = shard_size {
+                data.resize(shard_size as usize, 0);
+            }
+            if hash(&data) != chunk.hash {
+                corrupt.push(index);
+            }
+        }
+        if !corrupt.is_empty() {
+            for index in &corrupt {
+                self.written.remove(index);
+            }
+            self.state = write_state(&self.state_path, &hex::encode(file_hash), &self.written)?;
+            return Err(format!(
+                "{} chunks failed verification, run the download again to refetch them",
+                corrupt.len()
+            )
+            .into());
+        }
+
+        self.file.set_len(size)?;
+        self.file.sync_all()?;
+        fs::rename(&self.temp_path, &self.output)?;
+        fs::remove_file(&self.state_path)?;
+        Ok(())
+    }
+}
+
+/// Replaces the state file with one listing exactly the chunks in `written`
+/// and returns it, opened for appending further chunks.
+fn write_state(
+    path: &Path,
+    header: &str,
+    written: &BTreeMap<usize, WrittenChunk>,
+) -> io::Result<fs::File> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut state = fs::File::create(&tmp_path)?;
+    writeln!(state, "{}", header)?;
+    for (&index, chunk) in written {
+        writeln!(state, "{}", state_line(index, chunk))?;
+    }
+    state.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    fs::OpenOptions::new().append(true).open(path)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn state_line(index: usize, chunk: &WrittenChunk) -> String {
+    format!(
+        "{} {} {} {}",
+        index,
+        chunk.offset,
+        chunk.len,
+        hex::encode(&chunk.hash)
+    )
+}
+
+fn parse_state_line(line: &str) -> Option<(usize, WrittenChunk)> {
+    let mut fields = line.split(' ');
+    let index = fields.next()?.parse().ok()?;
+    let chunk = WrittenChunk {
+        offset: fields.next()?.parse().ok()?,
+        len: fields.next()?.parse().ok()?,
+        hash: hex::decode(fields.next()?).ok()?,
+    };
+    fields.next().is_none().then_some((index, chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{connect, spawn_node, test_config};
+
+    #[test]
+    fn resumes_and_moves_into_place_once_verified() {
+        let dir = std::env::temp_dir().join(format!("ufs-download-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("file.bin");
+        let chunks = [vec![1; 10], vec![2; 10], vec![3; 5]];
+        let chunk_hashes: Vec<Vec<u8>> = chunks.iter().map(|c| hash(c)).collect();
+
+        let mut partial = PartialDownload::open(&output, b"file").unwrap();
+        partial.write(0, 0, &chunks[0], &chunk_hashes[0]).unwrap();
+        partial.write(1, 10, &chunks[1], &chunk_hashes[1]).unwrap();
+        drop(partial);
+
+        // another file's state is ignored
+        assert_eq!(
+            PartialDownload::open(&output, b"other")
+                .unwrap()
+                .chunks_written(),
+            0
+        );
+
+        let mut partial = PartialDownload::open(&output, b"file").unwrap();
+        partial.write(0, 0, &chunks[0], &chunk_hashes[0]).unwrap();
+        partial.write(1, 10, &[9; 10], &chunk_hashes[1]).unwrap();
+        drop(partial);
+
+        let mut partial = PartialDownload::open(&output, b"file").unwrap();
+        assert_eq!(partial.written(1), Some((10, 10)));
+        partial.write(2, 20, &chunks[2], &chunk_hashes[2]).unwrap();
+        assert!(partial.finish(b"file", 25, None).is_err());
+        assert!(!output.exists());
+
+        // only the corrupt chunk is fetched again
+        let mut partial = PartialDownload::open(&output, b"file").unwrap();
+        assert_eq!(partial.chunks_written(), 2);
+        partial.write(1, 10, &chunks[1], &chunk_hashes[1]).unwrap();
+        partial.finish(b"file", 25, None).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), chunks.concat());
+        assert!(!with_suffix(&output, ".part.state").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunks_fail_over_to_other_providers_and_then_to_replicas() {
+        let a = spawn_node(test_config()).await;
+        let b = spawn_node(test_config()).await;
+        let node = spawn_node(test_config()).await;
+        let replica = spawn_node(test_config()).await;
+        connect(&[&a, &b, &node, &replica]).await;
+        let chunks: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i; 1000]).collect();
+        let chunk_hashes: Vec<Vec<u8>> = chunks.iter().map(|c| hash(c)).collect();
+        for (chunk_hash, chunk) in chunk_hashes.iter().zip(&chunks) {
+            a.store_chunk(chunk_hash, chunk).await.unwrap();
+            b.store_chunk(chunk_hash, chunk).await.unwrap();
+        }
+        let providers: Vec<ProviderRecord> = [&a, &b]
+            .iter()
+            .map(|n| n.own_record().unwrap().into())
+            .collect();
+        let client = PeerServiceClient::connect(node.address().unwrap())
+            .await
+            .unwrap();
+        let scheduler = Scheduler::new(&providers, client, 4);
+        // `a` looks far faster, so it gets practically every first request
+        {
+            let mut providers = scheduler.providers.lock().unwrap();
+            providers[0].throughput = Some(1e12);
+            providers[1].throughput = Some(1.0);
+        }
+        assert_eq!(
+            scheduler.fetch_chunk(&chunk_hashes[0]).await,
+            Some(chunks[0].clone())
+        );
+
+        // `a` loses the rest mid-download: each chunk is retried on `b`,
+        // until `a` is given up on
+        for chunk_hash in &chunk_hashes[1..] {
+            a.storage.delete_chunk(chunk_hash).unwrap();
+        }
+        for i in 1..5 {
+            assert_eq!(
+                scheduler.fetch_chunk(&chunk_hashes[i]).await,
+                Some(chunks[i].clone())
+            );
+        }
+        {
+            let providers = scheduler.providers.lock().unwrap();
+            assert_eq!(providers[0].chunks, 1);
+            assert_eq!(providers[0].failures, MAX_FAILURES);
+            assert_eq!(providers[1].chunks, 4);
+        }
+
+        // with neither provider left, the chunk comes from its replica
+        b.storage.delete_chunk(&chunk_hashes[5]).unwrap();
+        replica
+            .store_chunk(&chunk_hashes[5], &chunks[5])
+            .await
+            .unwrap();
+        replica
+            .provide(&chunk_hashes[5].as_slice().try_into().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            scheduler.fetch_chunk(&chunk_hashes[5]).await,
+            Some(chunks[5].clone())
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::str::FromStr;
+
+/// Reed-Solomon parameters for an erasure-coded upload, written `k+m`: every
+/// stripe of `k` data shards gets `m` parity shards, and any `k` of the
+/// `k + m` shards are enough to recover the stripe.
+#[derive(Clone, Copy, Debug)]
+pub struct ErasureParams {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+}
+
+impl FromStr for ErasureParams {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (k, m) = s
+            .split_once('+')
+            .ok_or_else(|| format!("expected DATA+PARITY, e.g. 4+2, got {}", s))?;
+        let data_shards: usize = k
+            .parse()
+            .map_err(|_| format!("invalid data shards {}", k))?;
+        let parity_shards: usize = m
+            .parse()
+            .map_err(|_| format!("invalid parity shards {}", m))?;
+        if data_shards == 0 || parity_shards == 0 || data_shards + parity_shards > 256 {
+            return Err("need at least one data and one parity shard, 256 in total at most".into());
+        }
+        Ok(Self {
+            data_shards,
+            parity_shards,
+        })
+    }
+}
+
+/// Splits `data` into stripes of `k` shards of `shard_size` bytes and appends
+/// `m` parity shards to each stripe. The last shards are zero-padded, so every
+/// shard has the same length. Shards are returned stripe by stripe.
+pub fn encode(
+    data: &[u8],
+    shard_size: usize,
+    params: ErasureParams,
+) -> Result<Vec<Vec<u8>>, reed_solomon_erasure::Error> {
+    let rs = ReedSolomon::new(params.data_shards, params.parity_shards)?;
+    let mut shards = Vec::new();
+    for stripe in data.chunks(shard_size * params.data_shards) {
+        let mut stripe_shards: Vec<Vec<u8>> = stripe
+            .chunks(shard_size)
+            .map(|chunk| {
+                let mut shard = chunk.to_vec();
+                shard.resize(shard_size, 0);
+                shard
+            })
+            .collect();
+        stripe_shards.resize(
+            params.data_shards + params.parity_shards,
+            vec![0; shard_size],
+        );
+        rs.encode(&mut stripe_shards)?;
+        shards.extend(stripe_shards);
+    }
+    Ok(shards)
+}
+
+/// Fills in the missing data shards of one stripe from whichever `k` of its
+/// shards are present.
+pub fn reconstruct(
+    shards: &mut [Option<Vec<u8>>],
+    params: ErasureParams,
+) -> Result<(), reed_solomon_erasure::Error> {
+    ReedSolomon::new(params.data_shards, params.parity_shards)?.reconstruct_data(shards)
+}
+
+/// Fills in every missing shard of one stripe, parity included, from
+/// whichever `k` of its shards are present.
+pub fn rebuild(
+    shards: &mut [Option<Vec<u8>>],
+    params: ErasureParams,
+) -> Result<(), reed_solomon_erasure::Error> {
+    ReedSolomon::new(params.data_shards, params.parity_shards)?.reconstruct(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: ErasureParams = ErasureParams {
+        data_shards: 4,
+        parity_shards: 2,
+    };
+
+    #[test]
+    fn parses_data_and_parity_counts() {
+        let params: ErasureParams = "10+4".parse().unwrap();
+        assert_eq!((params.data_shards, params.parity_shards), (10, 4));
+        assert!("10".parse::<ErasureParams>().is_err());
+        assert!("0+2".parse::<ErasureParams>().is_err());
+        assert!("200+100".parse::<ErasureParams>().is_err());
+    }
+
+    #[test]
+    fn pads_the_last_stripe() {
+        let data: Vec<u8> = (0..=255).cycle().take(50).collect();
+        let shards = encode(&data, 8, PARAMS).unwrap();
+        // 50 bytes = 7 shards of 8 bytes, i.e. two stripes
+        assert_eq!(shards.len(), 12);
+        assert!(shards.iter().all(|s| s.len() == 8));
+        assert_eq!(shards[0..4].concat()[..32], data[..32]);
+    }
+
+    #[test]
+    fn recovers_stripe_from_any_k_shards() {
+        let data: Vec<u8> = (0..=255).cycle().take(64).collect();
+        let shards = encode(&data, 16, PARAMS).unwrap();
+
+        for lost in [[0, 1], [0, 5], [3, 4], [4, 5]] {
+            let mut received: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+            for i in lost {
+                received[i] = None;
+            }
+            reconstruct(&mut received, PARAMS).unwrap();
+            let recovered: Vec<u8> = received[..4].iter().flatten().flatten().copied().collect();
+            assert_eq!(recovered, data);
+        }
+    }
+
+    #[test]
+    fn fails_with_fewer_than_k_shards() {
+        let shards = encode(&[7; 64], 16, PARAMS).unwrap();
+        let mut received: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        for shard in &mut received[..3] {
+            *shard = None;
+        }
+        assert!(reconstruct(&mut received, PARAMS).is_err());
+    }
+}
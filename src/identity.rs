@@ -0,0 +1,82 @@
+use crate::utils::hash;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+const KEY_FILE: &str = "node.key";
+
+/// A node's Ed25519 keypair. The node ID is the hash of its public key, so a
+/// node can't choose where it sits in the keyspace and peers can check the ID
+/// they are talking to against the key it presents.
+pub struct Identity {
+    keypair: Ed25519KeyPair,
+}
+
+impl Identity {
+    /// Generates a fresh keypair that only lives as long as the process.
+    pub fn generate() -> io::Result<Self> {
+        Self::from_pkcs8(&generate_pkcs8()?)
+    }
+
+    /// Loads the keypair from `node.key` in `data_dir`, generating and saving
+    /// one on first start. Without a data directory the identity is ephemeral.
+    pub fn load_or_generate(data_dir: Option<&Path>) -> io::Result<Self> {
+        let Some(dir) = data_dir else {
+            return Self::generate();
+        };
+        let path = dir.join(KEY_FILE);
+        match fs::read(&path) {
+            Ok(pkcs8) => Self::from_pkcs8(&pkcs8),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let pkcs8 = generate_pkcs8()?;
+                fs::create_dir_all(dir)?;
+                write_key(&path, &pkcs8)?;
+                log::info!("Generated new node key at {}", path.display());
+                Self::from_pkcs8(&pkcs8)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn from_pkcs8(pkcs8: &[u8]) -> io::Result<Self> {
+        let keypair = Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Self { keypair })
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.keypair.public_key().as_ref().try_into().unwrap()
+    }
+
+    pub fn node_id(&self) -> [u8; 32] {
+        node_id_for(&self.public_key())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.keypair.sign(message).as_ref().to_vec()
+    }
+}
+
+/// The node ID that belongs to `public_key`.
+pub fn node_id_for(public_key: &[u8; 32]) -> [u8; 32] {
+    hash(public_key).try_into().unwrap()
+}
+
+fn generate_pkcs8() -> io::Result<Vec<u8>> {
+    Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map(|doc| doc.as_ref().to_vec())
+        .map_err(|_| io::Error::other("failed to generate node key"))
+}
+
+/// Writes the private key so only the owner can read it.
+fn write_key(path: &Path, pkcs8: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(pkcs8)?;
+    file.sync_all()
+}
@@ -0,0 +1,369 @@
+use crate::dht::{xor_distance, Distance, Peer, K_VALUE};
+use crate::node::Node;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::time::Duration;
+
+pub type QueryError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What a single peer answered during a lookup.
+pub enum QueryResponse<V> {
+    /// The peer doesn't have what we're looking for but knows these peers.
+    Peers(Vec<Peer>),
+    /// The peer has the value; the lookup stops here.
+    Found(V),
+}
+
+pub enum LookupOutcome<V> {
+    Found(V),
+    /// The k closest peers that responded, nearest first.
+    Closest(Vec<Peer>),
+}
+
+#[derive(PartialEq)]
+enum State {
+    NotQueried,
+    InFlight,
+    Responded,
+}
+
+struct Candidate {
+    peer: Peer,
+    state: State,
+}
+
+/// What a lookup needs from the node running it, kept apart from [`Node`] so
+/// lookups can be run against fake peers.
+trait LookupHost {
+    fn id(&self) -> [u8; 32];
+    fn alpha(&self) -> usize;
+    fn rpc_timeout(&self) -> Duration;
+    /// The known peers to start from.
+    async fn closest_known(&self, target: &[u8; 32]) -> Vec<Peer>;
+    async fn peer_responded(&self, peer: Peer);
+    async fn peer_failed(&self, peer: &Peer);
+}
+
+impl LookupHost for Node {
+    fn id(&self) -> [u8; 32] {
+        self.id
+    }
+
+    fn alpha(&self) -> usize {
+        self.config.alpha
+    }
+
+    fn rpc_timeout(&self) -> Duration {
+        self.config.rpc_timeout
+    }
+
+    async fn closest_known(&self, target: &[u8; 32]) -> Vec<Peer> {
+        self.routing_table.lock().await.find_closest_peers(target)
+    }
+
+    async fn peer_responded(&self, peer: Peer) {
+        self.add_peer(peer).await;
+    }
+
+    async fn peer_failed(&self, peer: &Peer) {
+        self.report_failure(peer).await;
+    }
+}
+
+/// Runs an iterative Kademlia lookup for `target`, sending `query` to at most
+/// `alpha` peers at a time.
+///
+/// Each query is bounded by the node's RPC timeout. Peers that fail or time
+/// out are dropped from the candidate set and reported to the routing table,
+/// peers that answer are added to it. The lookup ends once the k closest
+/// remaining candidates have all responded, or as soon as one of them returns
+/// the value.
+pub async fn iterative_lookup<V, F, Fut>(
+    node: &Node,
+    target: &[u8; 32],
+    query: F,
+) -> LookupOutcome<V>
+where
+    F: Fn(Peer) -> Fut,
+    Fut: Future<Output = Result<QueryResponse<V>, QueryError>>,
+{
+    let (mut found, closest) = run_lookup(node, target, query, true).await;
+    match found.pop() {
+        Some(value) => LookupOutcome::Found(value),
+        None => LookupOutcome::Closest(closest),
+    }
+}
+
+/// Like [`iterative_lookup`], but keeps going after a peer returns the value
+/// and collects every value seen until the k closest candidates have all
+/// answered. Returns them with the closest peers that responded.
+pub async fn collecting_lookup<V, F, Fut>(
+    node: &Node,
+    target: &[u8; 32],
+    query: F,
+) -> (Vec<V>, Vec<Peer>)
+where
+    F: Fn(Peer) -> Fut,
+    Fut: Future<Output = Result<QueryResponse<V>, QueryError>>,
+{
+    run_lookup(node, target, query, false).await
+}
+
+async fn run_lookup<V, F, Fut>(
+    node: &impl LookupHost,
+    target: &[u8; 32],
+    query: F,
+    stop_at_first: bool,
+) -> (Vec<V>, Vec<Peer>)
+where
+    F: Fn(Peer) -> Fut,
+    Fut: Future<Output = Result<QueryResponse<V>, QueryError>>,
+{
+    let alpha = node.alpha().max(1);
+    let rpc_timeout = node.rpc_timeout();
+
+    let mut candidates: BTreeMap<Distance, Candidate> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for peer in node.closest_known(target).await {
+        seen.insert(peer.node_id);
+        candidates.insert(
+            xor_distance(&peer.node_id, target),
+            Candidate {
+                peer,
+                state: State::NotQueried,
+            },
+        );
+    }
+
+    let mut in_flight = FuturesUnordered::new();
+    let mut found = Vec::new();
+
+    loop {
+        let mut closest = candidates.values().take(K_VALUE);
+        if closest.all(|c| c.state == State::Responded) {
+            break;
+        }
+
+        // top up the in-flight queries from the closest peers not yet asked
+        for candidate in candidates.values_mut().take(K_VALUE) {
+            if in_flight.len() >= alpha {
+                break;
+            }
+            if candidate.state != State::NotQueried {
+                continue;
+            }
+            candidate.state = State::InFlight;
+            let peer = candidate.peer.clone();
+            let request = query(peer.clone());
+            in_flight.push(async move {
+                let result = match tokio::time::timeout(rpc_timeout, request).await {
+                    Ok(result) => result,
+                    Err(elapsed) => Err(elapsed.into()),
+                };
+                (peer, result)
+            });
+        }
+
+        let Some((peer, result)) = in_flight.next().await else {
+            break;
+        };
+        let distance = xor_distance(&peer.node_id, target);
+
+        match result {
+            Ok(QueryResponse::Found(value)) => {
+                node.peer_responded(peer).await;
+                found.push(value);
+                if stop_at_first {
+                    break;
+                }
+                // a peer with the value doesn't name closer peers, so it
+                // only counts as having responded
+                if let Some(candidate) = candidates.get_mut(&distance) {
+                    candidate.state = State::Responded;
+                }
+            }
+            Ok(QueryResponse::Peers(peers)) => {
+                if let Some(candidate) = candidates.get_mut(&distance) {
+                    candidate.state = State::Responded;
+                }
+                for p in peers {
+                    if p.node_id == node.id() || !seen.insert(p.node_id) {
+                        continue;
+                    }
+                    candidates.insert(
+                        xor_distance(&p.node_id, target),
+                        Candidate {
+                            peer: p,
+                            state: State::NotQueried,
+                        },
+                    );
+                }
+                node.peer_responded(peer).await;
+            }
+            Err(e) => {
+                log::info!("Lookup query to {} failed: {}", peer.address, e);
+                candidates.remove(&distance);
+                node.peer_failed(&peer).await;
+            }
+        }
+    }
+
+    let closest = candidates
+        .into_values()
+        .filter(|c| c.state == State::Responded)
+        .take(K_VALUE)
+        .map(|c| c.peer)
+        .collect();
+    (found, closest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    struct FakeHost {
+        alpha: usize,
+        rpc_timeout: Duration,
+        known: Vec<Peer>,
+        responded: Mutex<Vec<u8>>,
+        failed: Mutex<Vec<u8>>,
+    }
+
+    impl FakeHost {
+        fn new(known: impl IntoIterator<Item = u8>, rpc_timeout: Duration) -> Self {
+            Self {
+                alpha: 3,
+                rpc_timeout,
+                known: known.into_iter().map(peer).collect(),
+                responded: Mutex::default(),
+                failed: Mutex::default(),
+            }
+        }
+    }
+
+    impl LookupHost for FakeHost {
+        fn id(&self) -> [u8; 32] {
+            [0; 32]
+        }
+
+        fn alpha(&self) -> usize {
+            self.alpha
+        }
+
+        fn rpc_timeout(&self) -> Duration {
+            self.rpc_timeout
+        }
+
+        async fn closest_known(&self, _target: &[u8; 32]) -> Vec<Peer> {
+            self.known.clone()
+        }
+
+        async fn peer_responded(&self, peer: Peer) {
+            self.responded.lock().unwrap().push(peer.node_id[0]);
+        }
+
+        async fn peer_failed(&self, peer: &Peer) {
+            self.failed.lock().unwrap().push(peer.node_id[0]);
+        }
+    }
+
+    // peers are numbered by their distance to the all-zero target
+    fn peer(n: u8) -> Peer {
+        Peer {
+            node_id: [n; 32],
+            address: format!("peer-{}", n),
+            public_key: [0; 32],
+        }
+    }
+
+    fn numbers(peers: &[Peer]) -> Vec<u8> {
+        peers.iter().map(|p| p.node_id[0]).collect()
+    }
+
+    #[tokio::test]
+    async fn converges_on_the_closest_peers_with_at_most_alpha_in_flight() {
+        let host = FakeHost::new(40..50, Duration::from_secs(5));
+        let asked = Mutex::new(Vec::new());
+        let in_flight = AtomicUsize::new(0);
+        let most_in_flight = AtomicUsize::new(0);
+
+        let (found, closest) = run_lookup(
+            &host,
+            &[0; 32],
+            |p: Peer| {
+                let (asked, in_flight, most_in_flight) = (&asked, &in_flight, &most_in_flight);
+                async move {
+                    let n = p.node_id[0];
+                    asked.lock().unwrap().push(n);
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_in_flight.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(2)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    // every peer knows the five just closer than itself
+                    let closer = n.saturating_sub(5).max(1)..n;
+                    Ok(QueryResponse::<u32>::Peers(closer.map(peer).collect()))
+                }
+            },
+            false,
+        )
+        .await;
+
+        assert!(found.is_empty());
+        let expected: Vec<u8> = (1..=K_VALUE as u8).collect();
+        assert_eq!(numbers(&closest), expected);
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), host.alpha);
+        let mut asked = asked.into_inner().unwrap();
+        let queries = asked.len();
+        asked.sort_unstable();
+        asked.dedup();
+        assert_eq!(asked.len(), queries, "a peer was asked twice");
+    }
+
+    #[tokio::test]
+    async fn failed_and_silent_peers_are_dropped_and_reported() {
+        let host = FakeHost::new(1..=5, Duration::from_millis(50));
+        let (_, closest) = run_lookup(
+            &host,
+            &[0; 32],
+            |p: Peer| async move {
+                match p.node_id[0] {
+                    1 => Err("connection refused".into()),
+                    2 => futures::future::pending().await,
+                    _ => Ok(QueryResponse::<u32>::Peers(Vec::new())),
+                }
+            },
+            false,
+        )
+        .await;
+
+        assert_eq!(numbers(&closest), vec![3, 4, 5]);
+        let mut failed = host.failed.into_inner().unwrap();
+        failed.sort_unstable();
+        assert_eq!(failed, vec![1, 2]);
+        let mut responded = host.responded.into_inner().unwrap();
+        responded.sort_unstable();
+        assert_eq!(responded, vec![3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_value_only_when_asked_to() {
+        let query = |p: Peer| async move {
+            match p.node_id[0] {
+                n @ (2 | 4) => Ok(QueryResponse::Found(n as u32)),
+                _ => Ok(QueryResponse::Peers(Vec::new())),
+            }
+        };
+        let host = FakeHost::new(1..=6, Duration::from_secs(5));
+
+        let (found, _) = run_lookup(&host, &[0; 32], query, true).await;
+        assert_eq!(found.len(), 1);
+
+        let (mut found, closest) = run_lookup(&host, &[0; 32], query, false).await;
+        found.sort_unstable();
+        assert_eq!(found, vec![2, 4]);
+        assert_eq!(numbers(&closest), vec![1, 2, 3, 4, 5, 6]);
+    }
+}
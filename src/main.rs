@@ -1,13 +1,28 @@
 use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
+mod auth;
+mod chunking;
 mod cli;
 mod dht;
+mod download;
+mod erasure;
+mod identity;
+mod lookup;
+mod maintenance;
+mod manifest;
 mod utils;
 
 mod node;
+mod range;
+mod records;
+mod repair;
+mod replication;
 mod server;
 mod storage;
+#[cfg(test)]
+mod testing;
 
 pub mod storage_proto {
     tonic::include_proto!("storage");
@@ -27,11 +42,62 @@ enum Commands {
 }
 
 #[derive(Parser, Debug)]
-struct ServerArgs {
+pub struct ServerArgs {
     #[arg(long, default_value_t = 42069)]
-    port: u16,
+    pub port: u16,
+    /// Address to accept connections on; defaults to `0.0.0.0:<port>`.
     #[arg(long)]
-    bootstrap_peer: Option<String>,
+    pub listen: Option<SocketAddr>,
+    /// URL other peers should use to reach this node, e.g.
+    /// `http://203.0.113.7:42069`. If omitted it is taken from `--listen`
+    /// or learned from how peers see us.
+    #[arg(long)]
+    pub advertise_addr: Option<String>,
+    #[arg(long)]
+    pub bootstrap_peer: Option<String>,
+    /// Also serve files over HTTP on this address, e.g. `127.0.0.1:8080`:
+    /// `GET /ipfs-style/<file_hash>` to read one, `PUT /ipfs-style/<name>`
+    /// to store one.
+    #[arg(long)]
+    pub http_listen: Option<SocketAddr>,
+    /// Directory to persist chunks, metadata and DHT values in.
+    /// Without it everything is kept in memory and lost on restart.
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+    /// How to lay out the data directory; ignored without `--data-dir`.
+    #[arg(long, value_enum, default_value_t = storage::BackendKind::Fs)]
+    pub storage_backend: storage::BackendKind,
+    /// Number of concurrent queries per DHT lookup.
+    #[arg(long, default_value_t = 3)]
+    pub lookup_alpha: usize,
+    /// Deadline for each peer-to-peer RPC, in milliseconds.
+    #[arg(long, default_value_t = 5000)]
+    pub rpc_timeout_ms: u64,
+    /// Lifetime of provider records, in seconds.
+    #[arg(long, default_value_t = 24 * 60 * 60)]
+    pub record_ttl_secs: u64,
+    /// How often to re-announce the files this node provides, in seconds.
+    #[arg(long, default_value_t = 12 * 60 * 60)]
+    pub republish_interval_secs: u64,
+    /// How often to push held records to newly-closer peers, in seconds.
+    #[arg(long, default_value_t = 60 * 60)]
+    pub replicate_interval_secs: u64,
+    /// How often to drop expired records, in seconds.
+    #[arg(long, default_value_t = 10 * 60)]
+    pub expiry_interval_secs: u64,
+    /// How often to run routing table maintenance, in seconds.
+    #[arg(long, default_value_t = 60)]
+    pub maintenance_interval_secs: u64,
+    /// Refresh buckets that saw no lookup for this many seconds.
+    #[arg(long, default_value_t = 60 * 60)]
+    pub bucket_refresh_interval_secs: u64,
+    /// How many peers to copy each uploaded chunk and file metadata to.
+    #[arg(long, default_value_t = 3)]
+    pub replication_factor: usize,
+    /// How often to check that held chunks still have enough replicas, in
+    /// seconds.
+    #[arg(long, default_value_t = 60 * 60)]
+    pub repair_interval_secs: u64,
 }
 
 #[derive(Parser, Debug)]
@@ -47,16 +113,45 @@ enum CliCommands {
     Upload {
         #[arg(long)]
         path: PathBuf,
+        /// Erasure-code the file as DATA+PARITY shards per stripe, e.g. 4+2,
+        /// instead of storing plain chunks.
+        #[arg(long)]
+        erasure: Option<erasure::ErasureParams>,
+        /// Cut the file into content-defined chunks of MIN:AVG:MAX bytes
+        /// (default 64k:256k:1m), so unchanged parts of a new version of a
+        /// file reuse the chunks of the old one.
+        #[arg(
+            long,
+            value_name = "MIN:AVG:MAX",
+            num_args = 0..=1,
+            default_missing_value = "64k:256k:1m",
+            conflicts_with = "erasure"
+        )]
+        cdc: Option<chunking::CdcParams>,
     },
     Download {
         #[arg(long)]
         hash: String,
         #[arg(long)]
         output: PathBuf,
+        /// How many chunks to fetch at once, spread across the providers.
+        #[arg(long, default_value_t = 32)]
+        window: usize,
+    },
+    /// Print part of a stored file to stdout.
+    Cat {
+        #[arg(long)]
+        hash: String,
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        /// How many bytes to print; the rest of the file if not given.
+        #[arg(long)]
+        length: Option<u64>,
     },
     ListFiles,
     ListPeers,
     ShowChunks,
+    RepairStats,
 }
 
 #[tokio::main]
@@ -67,7 +162,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match args.command {
         Commands::Server(server_args) => {
-            server::start_server(server_args.port, server_args.bootstrap_peer).await?;
+            server::start_server(server_args).await?;
         }
         Commands::Cli(cli_args) => {
             cli::handle_cli_command(cli_args.node_addr, cli_args.command).await?;
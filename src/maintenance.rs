@@ -0,0 +1,50 @@
+use crate::node::Node;
+use crate::utils::{tick_or_shutdown, ticker};
+use tokio::sync::watch;
+
+impl Node {
+    /// Keeps the routing table healthy between lookups.
+    ///
+    /// Every maintenance interval, buckets that no lookup has touched within
+    /// the refresh interval get a FIND_NODE for a random ID in their range,
+    /// and their least recently seen peer is queued for a liveness check. If
+    /// the table has emptied out entirely we try the bootstrap peer again.
+    pub(crate) async fn run_maintenance(
+        self,
+        bootstrap_peer: Option<String>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let mut ticker = ticker(self.config.maintenance_interval);
+        while tick_or_shutdown(&mut ticker, &mut shutdown).await {
+            if self.routing_table.lock().await.is_empty() {
+                if let Some(addr) = &bootstrap_peer {
+                    log::info!("Routing table is empty, retrying bootstrap");
+                    if let Err(e) = self.bootstrap(addr).await {
+                        log::warn!("Failed to bootstrap with {}: {}", addr, e);
+                    }
+                }
+                continue;
+            }
+
+            let stale = self
+                .routing_table
+                .lock()
+                .await
+                .stale_buckets(self.config.bucket_refresh_interval);
+            for index in stale {
+                let (target, least_recent) = {
+                    let routing_table = self.routing_table.lock().await;
+                    (
+                        routing_table.random_id_in_bucket(index),
+                        routing_table.least_recently_seen(index),
+                    )
+                };
+                log::debug!("Refreshing bucket {}", index);
+                self.find_node(&target).await;
+                if let Some(peer) = least_recent {
+                    self.check_peer(peer).await;
+                }
+            }
+        }
+    }
+}
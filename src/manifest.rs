@@ -0,0 +1,351 @@
+use crate::storage_proto::ManifestNode;
+use prost::Message;
+use std::future::Future;
+
+/// How many child hashes a manifest node lists. Files with more chunks than
+/// this get a tree of manifest nodes between `FileInfo` and their chunks.
+pub const FANOUT: usize = 1024;
+
+/// Encodes a manifest node listing `children` and the bytes under each; the
+/// result is stored and addressed like any other chunk.
+pub fn encode_node(children: Vec<Vec<u8>>, sizes: Vec<u64>) -> Vec<u8> {
+    ManifestNode { children, sizes }.encode_to_vec()
+}
+
+pub fn decode_node(data: &[u8]) -> Result<ManifestNode, String> {
+    ManifestNode::decode(data).map_err(|e| format!("malformed manifest node: {}", e))
+}
+
+/// The top of a finished manifest tree.
+pub struct ManifestTop {
+    /// The hashes for `FileInfo.chunk_hashes`.
+    pub chunk_hashes: Vec<Vec<u8>>,
+    /// The bytes under each of `chunk_hashes`.
+    pub chunk_sizes: Vec<u64>,
+    /// How many levels of manifest nodes are below `chunk_hashes`.
+    pub depth: u32,
+    /// The last manifest nodes, still to be stored.
+    pub nodes: Vec<Vec<u8>>,
+}
+
+/// Builds a file's manifest tree bottom-up while its chunk hashes stream in,
+/// holding at most one partial node per level.
+///
+/// Every chunk sits at the same depth: the file's `chunk_hashes` are the
+/// hashes of the top-level manifest nodes, whose children are the hashes of
+/// the next level down, and so on until the chunks themselves.
+pub struct ManifestBuilder {
+    fanout: usize,
+    // pending child hashes per level; level 0 holds chunk hashes
+    levels: Vec<Vec<Vec<u8>>>,
+    // the bytes under each pending child
+    sizes: Vec<Vec<u64>>,
+}
+
+impl ManifestBuilder {
+    pub fn new(fanout: usize) -> Self {
+        Self {
+            fanout,
+            levels: vec![Vec::new()],
+            sizes: vec![Vec::new()],
+        }
+    }
+
+    /// Adds the hash and length of the file's next chunk. Returns the
+    /// manifest nodes it completed, which have to be stored as chunks.
+    pub fn push(&mut self, chunk_hash: Vec<u8>, len: usize) -> Vec<Vec<u8>> {
+        let mut nodes = Vec::new();
+        self.push_at(0, chunk_hash, len as u64, &mut nodes);
+        nodes
+    }
+
+    fn push_at(&mut self, level: usize, hash: Vec<u8>, size: u64, nodes: &mut Vec<Vec<u8>>) {
+        self.levels[level].push(hash);
+        self.sizes[level].push(size);
+        if self.levels[level].len() == self.fanout {
+            self.emit(level, nodes);
+        }
+    }
+
+    fn emit(&mut self, level: usize, nodes: &mut Vec<Vec<u8>>) {
+        let children = std::mem::take(&mut self.levels[level]);
+        let sizes = std::mem::take(&mut self.sizes[level]);
+        let size = sizes.iter().sum();
+        let node = encode_node(children, sizes);
+        if self.levels.len() == level + 1 {
+            self.levels.push(Vec::new());
+            self.sizes.push(Vec::new());
+        }
+        self.push_at(level + 1, crate::utils::hash(&node), size, nodes);
+        nodes.push(node);
+    }
+
+    /// Closes the partial nodes of every level below the top one.
+    pub fn finish(mut self) -> ManifestTop {
+        let mut nodes = Vec::new();
+        let mut level = 0;
+        while level + 1 < self.levels.len() {
+            if !self.levels[level].is_empty() {
+                self.emit(level, &mut nodes);
+            }
+            level += 1;
+        }
+        ManifestTop {
+            chunk_hashes: self.levels.pop().unwrap_or_default(),
+            chunk_sizes: self.sizes.pop().unwrap_or_default(),
+            depth: level as u32,
+            nodes,
+        }
+    }
+}
+
+/// Walks a manifest tree from `FileInfo` down to the chunks, in order,
+/// loading each manifest node only when the chunks under it are reached.
+pub struct ManifestWalker {
+    // the remaining child hashes of the current node on every level, from
+    // the root down
+    stack: Vec<std::vec::IntoIter<Vec<u8>>>,
+    depth: usize,
+}
+
+impl ManifestWalker {
+    pub fn new(chunk_hashes: Vec<Vec<u8>>, depth: u32) -> Self {
+        Self {
+            stack: vec![chunk_hashes.into_iter()],
+            depth: depth as usize,
+        }
+    }
+
+    /// Returns the hash of the file's next chunk, or `None` after the last.
+    ///
+    /// Manifest nodes are fetched through `load`, which must only return
+    /// data that matches the hash it was asked for; that is what makes
+    /// every level as trustworthy as the file hash.
+    pub async fn next<F, Fut>(&mut self, mut load: F) -> Result<Option<Vec<u8>>, String>
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = Option<Vec<u8>>>,
+    {
+        loop {
+            let at_chunks = self.stack.len() > self.depth;
+            let Some(level) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            match level.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(hash) if at_chunks => return Ok(Some(hash)),
+                Some(hash) => self.descend(hash, &mut load).await?,
+            }
+        }
+    }
+
+    /// Skips the first `index` chunks of a fresh walker, loading only the
+    /// manifest nodes on the way to chunk `index`. `fanout` must be the one
+    /// the tree was built with: every node but the last on each level is
+    /// full, so the path follows from the index alone.
+    pub async fn seek<F, Fut>(
+        &mut self,
+        index: usize,
+        fanout: usize,
+        mut load: F,
+    ) -> Result<(), String>
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = Option<Vec<u8>>>,
+    {
+        let mut index = index;
+        for level in (1..=self.depth as u32).rev() {
+            // how many chunks each hash on this level covers
+            let span = fanout.saturating_pow(level);
+            let Some(hash) = self.stack.last_mut().and_then(|l| l.nth(index / span)) else {
+                return Ok(());
+            };
+            index %= span;
+            self.descend(hash, &mut load).await?;
+        }
+        if let Some(level) = self.stack.last_mut() {
+            level.by_ref().take(index).for_each(drop);
+        }
+        Ok(())
+    }
+
+    /// Skips the chunks of a fresh walker that end before byte `offset`,
+    /// going by `sizes`, the bytes under each of the walker's top-level
+    /// hashes, and by the sizes the manifest nodes record. Returns where the
+    /// next chunk starts in the file. Content-defined chunks can't be found
+    /// by index, so this is how their range reads skip ahead.
+    pub async fn seek_offset<F, Fut>(
+        &mut self,
+        sizes: &[u64],
+        offset: u64,
+        mut load: F,
+    ) -> Result<u64, String>
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = Option<Vec<u8>>>,
+    {
+        let mut sizes = sizes.to_vec();
+        let mut position = 0;
+        for level in 0..=self.depth {
+            let mut skip = 0;
+            for size in &sizes {
+                if position + size > offset {
+                    break;
+                }
+                position += size;
+                skip += 1;
+            }
+            let Some(children) = self.stack.last_mut() else {
+                return Ok(position);
+            };
+            if level == self.depth {
+                children.by_ref().take(skip).for_each(drop);
+                return Ok(position);
+            }
+            let Some(hash) = children.nth(skip) else {
+                return Ok(position);
+            };
+            let node = load_node(hash, &mut load).await?;
+            if node.sizes.len() != node.children.len() {
+                return Err("manifest node doesn't record the sizes of its children".into());
+            }
+            sizes = node.sizes;
+            self.stack.push(node.children.into_iter());
+        }
+        Ok(position)
+    }
+
+    async fn descend<F, Fut>(&mut self, hash: Vec<u8>, load: &mut F) -> Result<(), String>
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = Option<Vec<u8>>>,
+    {
+        let node = load_node(hash, load).await?;
+        self.stack.push(node.children.into_iter());
+        Ok(())
+    }
+}
+
+async fn load_node<F, Fut>(hash: Vec<u8>, load: &mut F) -> Result<ManifestNode, String>
+where
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = Option<Vec<u8>>>,
+{
+    let node = load(hash.clone())
+        .await
+        .ok_or_else(|| format!("manifest node {} is unavailable", hex::encode(&hash)))?;
+    decode_node(&node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::hash;
+    use std::collections::HashMap;
+
+    // manifest nodes by hash
+    type Nodes = HashMap<Vec<u8>, Vec<u8>>;
+
+    /// Builds the tree for `count` chunks and returns the chunk hashes, the
+    /// root's children, the depth and every manifest node by hash.
+    fn build(count: u8, fanout: usize) -> (Vec<Vec<u8>>, Vec<Vec<u8>>, u32, Nodes) {
+        let (chunks, top, nodes) = build_sized(count, fanout);
+        (chunks, top.chunk_hashes, top.depth, nodes)
+    }
+
+    /// Like `build`, with chunk `i` being `i + 1` bytes long.
+    fn build_sized(count: u8, fanout: usize) -> (Vec<Vec<u8>>, ManifestTop, Nodes) {
+        let chunks: Vec<Vec<u8>> = (0..count).map(|i| hash(&[i])).collect();
+        let mut builder = ManifestBuilder::new(fanout);
+        let mut nodes = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            nodes.extend(builder.push(chunk.clone(), i + 1));
+        }
+        let top = builder.finish();
+        nodes.extend(top.nodes.iter().cloned());
+        let nodes = nodes.into_iter().map(|n| (hash(&n), n)).collect();
+        (chunks, top, nodes)
+    }
+
+    async fn walk(top: Vec<Vec<u8>>, depth: u32, nodes: &Nodes) -> Vec<Vec<u8>> {
+        let mut walker = ManifestWalker::new(top, depth);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = walker
+            .next(|h| std::future::ready(nodes.get(&h).cloned()))
+            .await
+            .unwrap()
+        {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn small_files_have_no_manifest_nodes() {
+        let (chunks, top, depth, nodes) = build(3, 4);
+        assert_eq!((depth, nodes.len()), (0, 0));
+        assert_eq!(top, chunks);
+    }
+
+    #[tokio::test]
+    async fn walks_back_every_chunk_in_order() {
+        for count in [4, 5, 16, 17, 100] {
+            let (chunks, top, depth, nodes) = build(count, 4);
+            assert!(top.len() <= 4);
+            assert!(depth > 0);
+            assert_eq!(walk(top, depth, &nodes).await, chunks);
+        }
+    }
+
+    #[tokio::test]
+    async fn seeks_to_any_chunk() {
+        let (chunks, top, depth, nodes) = build(100, 4);
+        for index in [0, 1, 15, 16, 63, 64, 99, 100] {
+            let mut walker = ManifestWalker::new(top.clone(), depth);
+            let mut loads = 0;
+            walker
+                .seek(index, 4, |h| {
+                    loads += 1;
+                    std::future::ready(nodes.get(&h).cloned())
+                })
+                .await
+                .unwrap();
+            assert!(loads <= depth as usize);
+            let next = walker
+                .next(|h| std::future::ready(nodes.get(&h).cloned()))
+                .await
+                .unwrap();
+            assert_eq!(next.as_ref(), chunks.get(index));
+        }
+    }
+
+    #[tokio::test]
+    async fn seeks_to_the_chunk_holding_an_offset() {
+        let (chunks, top, nodes) = build_sized(20, 4);
+        let (sizes, depth) = (top.chunk_sizes, top.depth);
+        assert_eq!(sizes.iter().sum::<u64>(), (1..=20).sum());
+        // chunk `i` starts at byte i * (i + 1) / 2
+        for (offset, index, start) in [(0, 0, 0), (2, 1, 1), (3, 2, 3), (45, 9, 45), (209, 19, 190)]
+        {
+            let mut walker = ManifestWalker::new(top.chunk_hashes.clone(), depth);
+            let load = |h| std::future::ready(nodes.get(&h).cloned());
+            assert_eq!(walker.seek_offset(&sizes, offset, load).await, Ok(start));
+            let next = walker.next(load).await.unwrap();
+            assert_eq!(next.as_ref(), chunks.get(index));
+        }
+        // past the end there's nothing left
+        let mut walker = ManifestWalker::new(top.chunk_hashes, depth);
+        let load = |h| std::future::ready(nodes.get(&h).cloned());
+        assert_eq!(walker.seek_offset(&sizes, 210, load).await, Ok(210));
+        assert_eq!(walker.next(load).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn missing_node_fails_the_walk() {
+        let (_, top, depth, _) = build(20, 4);
+        let mut walker = ManifestWalker::new(top, depth);
+        assert!(walker.next(|_| std::future::ready(None)).await.is_err());
+    }
+}
@@ -1,245 +1,659 @@
-use crate::dht::{Peer, RoutingTable, K_VALUE};
-use crate::storage::{FileInfo, Storage};
+use crate::auth::signed_request;
+use crate::dht::{Peer, RoutingTable};
+use crate::identity::{node_id_for, Identity};
+use crate::lookup::{collecting_lookup, iterative_lookup, LookupOutcome, QueryResponse};
+use crate::repair::RepairMetrics;
+use crate::storage::{merge_provider, FileInfo, ProviderRecord, StorageBackend};
 use crate::storage_proto::peer_service_client::PeerServiceClient;
-use crate::storage_proto::{FindNodeRequest, FindValueRequest, PeerMessage, PingRequest};
-use crate::utils::hash;
+use crate::storage_proto::{
+    find_value_response, FindNodeRequest, FindValueRequest, PeerMessage, PingRequest, StoreRequest,
+};
+use crate::utils::{check_peer_address, unix_time};
 use futures::future::join_all;
-use std::collections::HashSet;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::task::JoinHandle;
 use tonic::Request;
 
+/// Tunables for a node's network behaviour.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    /// How many lookup queries may be in flight at once.
+    pub alpha: usize,
+    /// How long any single peer RPC may take before the peer is considered
+    /// unresponsive.
+    pub rpc_timeout: Duration,
+    /// How long provider records stay valid, both the ones we publish and the
+    /// upper bound we accept from others.
+    pub record_ttl: Duration,
+    /// How often we re-announce the keys we provide ourselves.
+    pub republish_interval: Duration,
+    /// How often records we hold for others are pushed to peers that have
+    /// become closer to their key.
+    pub replicate_interval: Duration,
+    /// How often expired records are swept from storage.
+    pub expiry_interval: Duration,
+    /// How often the routing table maintenance task runs.
+    pub maintenance_interval: Duration,
+    /// How long a bucket may go without a lookup in its range before it is
+    /// refreshed.
+    pub bucket_refresh_interval: Duration,
+    /// How many peers each uploaded chunk and file metadata is copied to.
+    pub replication_factor: usize,
+    /// How often we check that the chunks we hold still have enough replicas.
+    pub repair_interval: Duration,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            alpha: 3,
+            rpc_timeout: Duration::from_secs(5),
+            record_ttl: Duration::from_secs(24 * 60 * 60),
+            republish_interval: Duration::from_secs(12 * 60 * 60),
+            replicate_interval: Duration::from_secs(60 * 60),
+            expiry_interval: Duration::from_secs(10 * 60),
+            maintenance_interval: Duration::from_secs(60),
+            bucket_refresh_interval: Duration::from_secs(60 * 60),
+            replication_factor: 3,
+            repair_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// For each DHT key, the peers we have already pushed its records to.
+pub(crate) type ReplicationLog = HashMap<Vec<u8>, HashSet<[u8; 32]>>;
+
+// how many distinct peers must see us at the same IP before we advertise it
+const ADDRESS_QUORUM: usize = 3;
+// how many peers' latest sightings of our IP are kept
+const MAX_ADDRESS_VOTES: usize = 32;
+
+/// The IP each peer last told us it sees us at, oldest first.
+type AddressVotes = VecDeque<([u8; 32], IpAddr)>;
+
+/// Keeps a started node's background tasks alive and stops them on request.
+pub struct NodeHandle {
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl NodeHandle {
+    /// Signals every background task to stop and waits for them to finish.
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        for task in self.tasks {
+            if let Err(e) = task.await {
+                log::warn!("Background task failed: {}", e);
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Node {
-    // the kademlia id
+    // the kademlia id, the hash of our public key
     pub id: [u8; 32],
-    // node address
-    pub address: String,
-    pub storage: Arc<Storage>,
+    pub identity: Arc<Identity>,
+    // the URL peers reach us on, once we know it
+    address: Arc<RwLock<Option<String>>>,
+    // whether the address was configured rather than learned from peers
+    address_fixed: bool,
+    // how peers have told us they see us
+    address_votes: Arc<RwLock<AddressVotes>>,
+    // the port we accept connections on, for addresses learned from peers
+    listen_port: u16,
+    pub storage: Arc<dyn StorageBackend>,
     pub routing_table: Arc<Mutex<RoutingTable>>,
+    pub config: NodeConfig,
+    // wakes the liveness checker when the routing table queues a check
+    pending_checks: Arc<Notify>,
+    // peers each key's records have already been replicated to
+    pub(crate) replicated_to: Arc<Mutex<ReplicationLog>>,
+    pub repair_metrics: Arc<RepairMetrics>,
 }
 
 impl Node {
-    pub fn new(address: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let id = hash(address.as_bytes()).try_into().unwrap();
-        let storage = Arc::new(Storage::new());
+    /// Creates a node accepting connections on `listen`.
+    ///
+    /// The address announced to peers is `advertise_addr` if given, else the
+    /// listen address if it names a specific interface. Otherwise the node
+    /// learns it once several peers agree on how they see us.
+    pub fn new(
+        listen: SocketAddr,
+        advertise_addr: Option<String>,
+        identity: Identity,
+        storage: Arc<dyn StorageBackend>,
+        config: NodeConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let id = identity.node_id();
         let routing_table = Arc::new(Mutex::new(RoutingTable::new(id)));
 
+        let address = match advertise_addr {
+            Some(addr) => {
+                check_peer_address(&addr)?;
+                Some(addr)
+            }
+            None if !listen.ip().is_unspecified() => Some(format!("http://{}", listen)),
+            None => None,
+        };
+
         Ok(Node {
             id,
-            address: address.to_string(),
+            identity: Arc::new(identity),
+            address_fixed: address.is_some(),
+            address: Arc::new(RwLock::new(address)),
+            address_votes: Arc::default(),
+            listen_port: listen.port(),
             storage,
             routing_table,
+            config,
+            pending_checks: Arc::new(Notify::new()),
+            replicated_to: Arc::default(),
+            repair_metrics: Arc::default(),
         })
     }
 
+    /// Spawns the node's background tasks and joins the network through
+    /// `bootstrap_peer`, if given. The returned handle stops the tasks again.
     pub async fn start(
         &self,
         bootstrap_peer: Option<String>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<NodeHandle, Box<dyn std::error::Error + Send + Sync>> {
+        let (shutdown, rx) = watch::channel(false);
+        let handle = NodeHandle {
+            shutdown,
+            tasks: vec![
+                tokio::spawn(self.clone().run_liveness_checks(rx.clone())),
+                tokio::spawn(self.clone().run_republish(rx.clone())),
+                tokio::spawn(self.clone().run_replicate(rx.clone())),
+                tokio::spawn(self.clone().run_expiry(rx.clone())),
+                tokio::spawn(self.clone().run_repair(rx.clone())),
+                tokio::spawn(self.clone().run_maintenance(bootstrap_peer.clone(), rx)),
+            ],
+        };
+
         if let Some(addr) = bootstrap_peer {
-            self.bootstrap(&addr).await?;
+            if let Err(e) = self.bootstrap(&addr).await {
+                handle.shutdown().await;
+                return Err(e);
+            }
         }
-        Ok(())
+        Ok(handle)
     }
 
-    async fn bootstrap(&self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub(crate) async fn bootstrap(
+        &self,
+        addr: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         log::info!("Bootstrapping with peer at {}", addr);
         let mut client = PeerServiceClient::connect(addr.to_string()).await?;
+        let ping = || {
+            self.signed(
+                "Ping",
+                PingRequest {
+                    peer: Some(self.peer_message()),
+                },
+            )
+        };
 
-        let response = client
-            .ping(Request::new(PingRequest {
-                peer: Some(PeerMessage {
-                    node_id: self.id.to_vec(),
-                    address: self.address.clone(),
-                }),
-            }))
-            .await?;
-
-        let bootstrap_node_id: [u8; 32] = response.into_inner().node_id.try_into().unwrap();
+        let pong = client.ping(ping()).await?.into_inner();
+        let bootstrap_node_id: [u8; 32] = pong
+            .node_id
+            .try_into()
+            .map_err(|_| "bootstrap peer returned a malformed node ID")?;
+        let public_key: [u8; 32] = pong
+            .public_key
+            .try_into()
+            .map_err(|_| "bootstrap peer returned a malformed public key")?;
+        if node_id_for(&public_key) != bootstrap_node_id {
+            return Err("bootstrap peer's node ID does not match its public key".into());
+        }
+        self.learn_observed_address(bootstrap_node_id, &pong.observed_address);
 
         let bootstrap_peer = Peer {
             node_id: bootstrap_node_id,
             address: addr.to_string(),
+            public_key,
         };
 
-        self.routing_table
-            .lock()
-            .await
-            .add_peer(bootstrap_peer)
-            .await;
+        self.add_peer(bootstrap_peer).await;
 
         // do a FIND_NODE on ourself to discover the network
-        self.find_node(&self.id).await?;
+        let peers = self.find_node(&self.id).await;
+
+        // one peer's word isn't enough to adopt an address, so ask the ones
+        // we found, then introduce ourselves again once they can reach us
+        if self.address().is_none() {
+            join_all(peers.iter().map(|peer| self.ping(peer))).await;
+            if self.address().is_some() {
+                join_all(peers.iter().map(|peer| self.ping(peer))).await;
+            }
+        }
 
         Ok(())
     }
 
-    pub async fn find_node(
-        &self,
-        target_id: &[u8; 32],
-    ) -> Result<Vec<Peer>, Box<dyn std::error::Error>> {
-        let mut closest_peers = self
-            .routing_table
-            .lock()
-            .await
-            .find_closest_peers(target_id);
-        let mut queried_peers = HashSet::new();
-        let mut results = Vec::new();
+    /// The URL peers can reach us on, if we know it yet.
+    pub fn address(&self) -> Option<String> {
+        self.address.read().unwrap().clone()
+    }
 
-        loop {
-            let mut futures = Vec::new();
-            let peers_to_query: Vec<Peer> = closest_peers
-                .iter()
-                .filter(|p| !queried_peers.contains(&p.node_id))
-                .take(K_VALUE)
-                .cloned()
-                .collect();
+    /// Counts `observer`'s report that it sees us at `ip`, and advertises
+    /// that IP once `ADDRESS_QUORUM` distinct peers agree on it, replacing an
+    /// earlier learned address. A configured address is never replaced, and
+    /// loopback, link-local and unspecified IPs are ignored since no other
+    /// host can dial them. Returns `true` if our address changed.
+    pub fn learn_address(&self, observer: [u8; 32], ip: IpAddr) -> bool {
+        if self.address_fixed || !is_dialable(ip) {
+            return false;
+        }
+        let candidate = format!("http://{}", SocketAddr::new(ip, self.listen_port));
+        if check_peer_address(&candidate).is_err() {
+            return false;
+        }
 
-            if peers_to_query.is_empty() {
-                break;
-            }
+        let mut votes = self.address_votes.write().unwrap();
+        votes.retain(|(peer, _)| *peer != observer);
+        votes.push_back((observer, ip));
+        if votes.len() > MAX_ADDRESS_VOTES {
+            votes.pop_front();
+        }
+        if votes.iter().filter(|(_, seen)| *seen == ip).count() < ADDRESS_QUORUM {
+            return false;
+        }
+
+        let mut address = self.address.write().unwrap();
+        if address.as_deref() == Some(candidate.as_str()) {
+            return false;
+        }
+        log::info!("Peers see us at {}, advertising it", candidate);
+        *address = Some(candidate);
+        true
+    }
+
+    /// Counts the `observed_address` of a PONG from `observer`.
+    fn learn_observed_address(&self, observer: [u8; 32], observed: &str) -> bool {
+        observed
+            .parse::<SocketAddr>()
+            .is_ok_and(|addr| self.learn_address(observer, addr.ip()))
+    }
 
-            for peer in peers_to_query {
-                queried_peers.insert(peer.node_id);
-                let future = async move {
-                    log::info!("Querying peer {:?} for target", peer.address);
-                    let mut client = PeerServiceClient::connect(peer.address).await?;
-                    let request = Request::new(FindNodeRequest {
-                        target_id: target_id.to_vec(),
-                    });
-                    let response = client.find_node(request).await?;
-                    let peers: Vec<Peer> = response
-                        .into_inner()
-                        .peers
-                        .into_iter()
-                        .map(|p| Peer {
-                            node_id: p.node_id.try_into().unwrap(),
-                            address: p.address,
-                        })
-                        .collect();
-                    Ok::<_, Box<dyn std::error::Error>>(peers)
-                };
-                futures.push(future);
+    /// How we introduce ourselves to other peers. The address is left empty
+    /// while we don't know it, so peers answer without adding us.
+    pub fn peer_message(&self) -> PeerMessage {
+        PeerMessage {
+            node_id: self.id.to_vec(),
+            address: self.address().unwrap_or_default(),
+            public_key: self.identity.public_key().to_vec(),
+        }
+    }
+
+    /// Signs an outgoing peer RPC with our node key.
+    pub fn signed<T: prost::Message>(&self, method: &str, message: T) -> Request<T> {
+        signed_request(&self.identity, method, message)
+    }
+
+    /// Records that we heard from `peer`. If its bucket is full, the liveness
+    /// check on the bucket's least recently seen peer runs in the background
+    /// so the routing table is never locked across a network round trip.
+    ///
+    /// Peers whose node ID isn't the hash of their public key are refused.
+    pub async fn add_peer(&self, peer: Peer) {
+        if node_id_for(&peer.public_key) != peer.node_id {
+            log::warn!("Refusing unverifiable peer {}", peer.address);
+            return;
+        }
+        let queued = self.routing_table.lock().await.add_peer(peer);
+        if queued {
+            self.pending_checks.notify_one();
+        }
+    }
+
+    /// Pings peers queued by the routing table and keeps or evicts them
+    /// depending on whether they answer.
+    async fn run_liveness_checks(self, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            tokio::select! {
+                _ = self.pending_checks.notified() => {}
+                _ = shutdown.changed() => return,
             }
 
-            let responses = join_all(futures).await;
-            let mut found_new_peers = false;
+            let peers = self.routing_table.lock().await.take_pending_checks();
+            let results = join_all(peers.iter().map(|peer| self.ping(peer))).await;
 
-            for res in responses {
-                if let Ok(peers) = res {
-                    for peer in peers {
-                        if !closest_peers.iter().any(|p| p.node_id == peer.node_id) {
-                            closest_peers.push(peer);
-                            found_new_peers = true;
-                        }
+            let mut routing_table = self.routing_table.lock().await;
+            for (peer, result) in peers.iter().zip(results) {
+                match result {
+                    Ok(()) => routing_table.mark_alive(&peer.node_id),
+                    Err(e) => {
+                        log::info!("Peer {} failed liveness check: {}", peer.address, e);
+                        routing_table.remove_peer(&peer.node_id);
                     }
                 }
             }
+        }
+    }
+
+    /// Sends a PING to `peer`, failing if it doesn't answer within the RPC
+    /// timeout.
+    pub async fn ping(&self, peer: &Peer) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let request = async {
+            let mut client = PeerServiceClient::connect(peer.address.clone()).await?;
+            let pong = client
+                .ping(self.signed(
+                    "Ping",
+                    PingRequest {
+                        peer: Some(self.peer_message()),
+                    },
+                ))
+                .await?
+                .into_inner();
+            self.learn_observed_address(peer.node_id, &pong.observed_address);
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        };
+        tokio::time::timeout(self.config.rpc_timeout, request).await?
+    }
+
+    /// Queues a liveness check on `peer` and wakes the checker.
+    pub(crate) async fn check_peer(&self, peer: Peer) {
+        let queued = self.routing_table.lock().await.queue_check(peer);
+        if queued {
+            self.pending_checks.notify_one();
+        }
+    }
 
-            closest_peers.sort_by_key(|p| crate::dht::xor_distance(&p.node_id, target_id));
-            results = closest_peers.clone();
+    /// Drops a peer that failed to answer us from the routing table.
+    pub async fn report_failure(&self, peer: &Peer) {
+        self.routing_table.lock().await.remove_peer(&peer.node_id);
+    }
 
-            if !found_new_peers {
-                break;
+    /// Finds the k closest peers to `target_id` with an iterative lookup.
+    pub async fn find_node(&self, target_id: &[u8; 32]) -> Vec<Peer> {
+        self.routing_table.lock().await.mark_refreshed(target_id);
+        let outcome = iterative_lookup(self, target_id, |peer: Peer| {
+            let request = self.signed(
+                "FindNode",
+                FindNodeRequest {
+                    target_id: target_id.to_vec(),
+                },
+            );
+            async move {
+                log::info!("Querying peer {:?} for target", peer.address);
+                let mut client = PeerServiceClient::connect(peer.address).await?;
+                let response = client.find_node(request).await?;
+                Ok::<QueryResponse<()>, _>(QueryResponse::Peers(parse_peers(
+                    response.into_inner().peers,
+                )))
             }
-        }
+        })
+        .await;
 
-        Ok(results.into_iter().take(K_VALUE).collect())
+        match outcome {
+            LookupOutcome::Closest(peers) => peers,
+            LookupOutcome::Found(()) => unreachable!("FIND_NODE never returns a value"),
+        }
     }
 
     /// Perform a find_value operation on the DHT.
     /// this is how this works:
     /// 1. Find the closest peers to the target ID.
     /// 2. Query those peers for their closest peers to the target ID.
-    /// 3. Repeat until we have got the value or we've queried all peers.
+    /// 3. Repeat until all of the closest peers have answered, collecting the
+    ///    providers every one of them knows about.
+    ///
+    /// Returns every provider record known for `key`, ours included, newest
+    /// first; the list is empty if nobody provides it.
+    pub async fn find_value(&self, key: &[u8; 32]) -> Vec<ProviderRecord> {
+        let (found, _) = collecting_lookup(self, key, |peer: Peer| {
+            let request = self.signed("FindValue", FindValueRequest { key: key.to_vec() });
+            async move {
+                let mut client = PeerServiceClient::connect(peer.address).await?;
+                let response = client.find_value(request).await?;
+                Ok(match response.into_inner().result {
+                    Some(find_value_response::Result::Providers(list)) => QueryResponse::Found(
+                        list.providers
+                            .into_iter()
+                            .filter_map(|p| ProviderRecord::try_from(p).ok())
+                            .collect::<Vec<_>>(),
+                    ),
+                    Some(find_value_response::Result::ClosestPeers(p)) => {
+                        QueryResponse::Peers(parse_peers(p.peers))
+                    }
+                    None => QueryResponse::Peers(Vec::new()),
+                })
+            }
+        })
+        .await;
 
-    pub async fn find_value(
+        let now = unix_time();
+        let mut providers = self.local_providers(key);
+        for record in found.iter().flatten().filter(|r| !r.is_expired(now)) {
+            merge_provider(&mut providers, record);
+        }
+        providers
+    }
+
+    /// Announces this node as a provider of `key`: the record is kept locally
+    /// and sent to the k closest peers. Returns how many peers accepted it.
+    pub async fn provide(
         &self,
         key: &[u8; 32],
-    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
-        let mut closest_peers = self.routing_table.lock().await.find_closest_peers(key);
-        let mut queried_peers = HashSet::new();
-
-        loop {
-            // store the futures of each request to the closest peers
-            let mut futures = Vec::new();
-            let peers_to_query: Vec<Peer> = closest_peers
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let record = self
+            .own_record()
+            .ok_or("this node doesn't know its own address yet, set --advertise-addr")?;
+        self.add_provider(key, &record).await?;
+
+        let peers = self.find_node(key).await;
+        let results = join_all(
+            peers
                 .iter()
-                .filter(|p| !queried_peers.contains(&p.node_id))
-                .take(K_VALUE)
-                .cloned()
-                .collect();
-
-            if peers_to_query.is_empty() {
-                break;
-            }
-
-            for peer in peers_to_query {
-                queried_peers.insert(peer.node_id);
-                let future = async move {
-                    let mut client = PeerServiceClient::connect(peer.address).await?;
-                    let request = Request::new(FindValueRequest { key: key.to_vec() });
-                    let response = client.find_value(request).await?;
-                    Ok::<_, Box<dyn std::error::Error>>(response.into_inner())
-                };
-                futures.push(future);
+                .map(|peer| self.store_provider(peer, key, &record)),
+        )
+        .await;
+
+        let mut announced = 0;
+        for (peer, result) in peers.iter().zip(results) {
+            match result {
+                Ok(()) => announced += 1,
+                Err(e) => log::warn!("Failed to announce to {}: {}", peer.address, e),
             }
+        }
+        Ok(announced)
+    }
 
-            let responses = join_all(futures).await;
-            let mut found_new_peers = false;
-
-            for res in responses {
-                if let Ok(response) = res {
-                    if let Some(result) = response.result {
-                        match result {
-                            crate::storage_proto::find_value_response::Result::Value(v) => {
-                                return Ok(Some(v));
-                            }
-                            crate::storage_proto::find_value_response::Result::ClosestPeers(p) => {
-                                for peer_msg in p.peers {
-                                    let peer = Peer {
-                                        node_id: peer_msg.node_id.try_into().unwrap(),
-                                        address: peer_msg.address,
-                                    };
-                                    if !closest_peers.iter().any(|p| p.node_id == peer.node_id) {
-                                        closest_peers.push(peer);
-                                        found_new_peers = true;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    /// A fresh provider record pointing at this node, if we know our address.
+    pub fn own_record(&self) -> Option<ProviderRecord> {
+        let now = unix_time();
+        Some(ProviderRecord {
+            node_id: self.id,
+            address: self.address()?,
+            timestamp: now,
+            expires_at: now + self.config.record_ttl.as_secs(),
+        })
+    }
 
-            closest_peers.sort_by_key(|p| crate::dht::xor_distance(&p.node_id, key));
+    /// The unexpired provider records we hold for `key`.
+    pub fn local_providers(&self, key: &[u8]) -> Vec<ProviderRecord> {
+        let now = unix_time();
+        self.storage
+            .get_providers(key)
+            .into_iter()
+            .filter(|r| !r.is_expired(now))
+            .collect()
+    }
 
-            if !found_new_peers {
-                break;
-            }
+    /// Stores a provider record received from a peer, capping its lifetime
+    /// at our own record TTL. Already expired records are ignored.
+    pub async fn accept_provider(
+        &self,
+        key: &[u8],
+        mut record: ProviderRecord,
+    ) -> std::io::Result<()> {
+        let now = unix_time();
+        if record.is_expired(now) {
+            return Ok(());
         }
+        record.expires_at = record
+            .expires_at
+            .min(now + self.config.record_ttl.as_secs());
+        self.add_provider(key, &record).await
+    }
 
-        Ok(None)
+    /// Sends a STORE of one provider record to `peer`.
+    pub(crate) async fn store_provider(
+        &self,
+        peer: &Peer,
+        key: &[u8; 32],
+        record: &ProviderRecord,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let request = async {
+            let mut client = PeerServiceClient::connect(peer.address.clone()).await?;
+            client
+                .store(self.signed(
+                    "Store",
+                    StoreRequest {
+                        key: key.to_vec(),
+                        provider: Some(record.clone().into()),
+                    },
+                ))
+                .await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        };
+        tokio::time::timeout(self.config.rpc_timeout, request).await?
+    }
+
+    /// Runs a write to storage on the blocking pool: the durable backends
+    /// fsync every write, which would otherwise stall an async worker.
+    pub(crate) async fn write_storage<T, F>(&self, write: F) -> std::io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn StorageBackend) -> std::io::Result<T> + Send + 'static,
+    {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || write(storage.as_ref()))
+            .await
+            .map_err(std::io::Error::other)?
     }
 
-    pub fn store_chunk(&self, hash: &[u8], data: &[u8]) {
-        self.storage.store_chunk(hash, data)
+    pub async fn store_chunk(&self, hash: &[u8], data: &[u8]) -> std::io::Result<()> {
+        let (hash, data) = (hash.to_vec(), data.to_vec());
+        self.write_storage(move |storage| storage.store_chunk(&hash, &data))
+            .await
+    }
+
+    pub async fn delete_chunk(&self, hash: &[u8]) -> std::io::Result<bool> {
+        let hash = hash.to_vec();
+        self.write_storage(move |storage| storage.delete_chunk(&hash))
+            .await
+    }
+
+    pub async fn add_provider(&self, key: &[u8], record: &ProviderRecord) -> std::io::Result<()> {
+        let (key, record) = (key.to_vec(), record.clone());
+        self.write_storage(move |storage| storage.add_provider(&key, &record))
+            .await
     }
 
     pub fn get_chunk(&self, hash: &[u8]) -> Option<Vec<u8>> {
         self.storage.get_chunk(hash)
     }
 
-    pub fn store_metadata(&self, hash: &[u8], metadata: &FileInfo) {
-        self.storage.store_metadata(hash, metadata)
+    pub async fn store_metadata(&self, hash: &[u8], encoded: &[u8]) -> std::io::Result<()> {
+        let (hash, encoded) = (hash.to_vec(), encoded.to_vec());
+        self.write_storage(move |storage| storage.store_metadata(&hash, &encoded))
+            .await
     }
 
     pub fn get_metadata(&self, hash: &[u8]) -> Option<FileInfo> {
         self.storage.get_metadata(hash)
     }
 
+    pub fn get_metadata_bytes(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        self.storage.get_metadata_bytes(hash)
+    }
+
     pub fn get_all_metadata(&self) -> Vec<FileInfo> {
         self.storage.get_all_metadata()
     }
 }
+
+/// Converts the peers in an RPC response, skipping any with a malformed ID.
+fn parse_peers(peers: Vec<PeerMessage>) -> Vec<Peer> {
+    peers
+        .into_iter()
+        .filter_map(|p| Peer::try_from(p).ok())
+        .collect()
+}
+
+/// Whether another host could reach us at `ip`.
+fn is_dialable(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let link_local = match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    };
+    !(ip.is_loopback() || ip.is_unspecified() || link_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    fn unaddressed_node() -> Node {
+        Node::new(
+            "0.0.0.0:7001".parse().unwrap(),
+            None,
+            Identity::generate().unwrap(),
+            Arc::new(MemoryBackend::new()),
+            NodeConfig::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn address_is_learned_once_enough_peers_agree() {
+        let node = unaddressed_node();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert!(!node.learn_address([1; 32], ip));
+        // the same peer saying it again doesn't count twice
+        assert!(!node.learn_address([1; 32], ip));
+        assert!(!node.learn_address([2; 32], "198.51.100.1".parse().unwrap()));
+        assert!(!node.learn_address([3; 32], ip));
+        assert_eq!(node.address(), None);
+        assert!(node.learn_address([4; 32], ip));
+        assert_eq!(node.address().as_deref(), Some("http://203.0.113.7:7001"));
+
+        // a new address needs a quorum of its own
+        let moved: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(!node.learn_address([5; 32], moved));
+        assert!(node.learn_address([6; 32], moved));
+        assert_eq!(node.address().as_deref(), Some("http://198.51.100.1:7001"));
+    }
+
+    #[test]
+    fn undialable_addresses_are_never_learned() {
+        let node = unaddressed_node();
+        for ip in [
+            "127.0.0.1",
+            "::1",
+            "169.254.1.1",
+            "fe80::1",
+            "::",
+            "::ffff:127.0.0.1",
+        ] {
+            for peer in 1..=ADDRESS_QUORUM as u8 {
+                assert!(
+                    !node.learn_address([peer; 32], ip.parse().unwrap()),
+                    "{}",
+                    ip
+                );
+            }
+        }
+        assert_eq!(node.address(), None);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::chunking::CHUNK_SIZE;
+use crate::erasure::{self, ErasureParams};
+use crate::lookup::QueryError;
+use crate::manifest::{ManifestWalker, FANOUT};
+use crate::node::Node;
+use crate::storage::FileInfo;
+use crate::storage_proto::peer_service_client::PeerServiceClient;
+use crate::storage_proto::{GetChunkRequest, GetFileMetadataRequest};
+use crate::utils::hash;
+use std::future::Future;
+use tokio::sync::mpsc;
+use tonic::transport::Channel;
+
+// pieces read ahead of what the reader has taken
+const READ_QUEUE: usize = 4;
+
+impl Node {
+    /// Returns a file's metadata from local storage, or else from the first
+    /// provider of the file hash that serves metadata matching it.
+    pub async fn find_metadata(&self, file_hash: &[u8; 32]) -> Option<FileInfo> {
+        if let Some(metadata) = self.get_metadata(file_hash) {
+            return Some(metadata);
+        }
+        let data = self
+            .fetch_from_providers(file_hash, |mut client| async move {
+                let request = self.signed(
+                    "GetFileMetadata",
+                    GetFileMetadataRequest {
+                        file_hash: file_hash.to_vec(),
+                    },
+                );
+                Ok(client
+                    .get_file_metadata(request)
+                    .await?
+                    .into_inner()
+                    .metadata)
+            })
+            .await?;
+        FileInfo::decode(&data).ok()
+    }
+
+    /// Reads `length` bytes of a file from `offset` on, clamped to its end.
+    /// The bytes arrive in pieces of at most a chunk, followed by an error
+    /// if the rest of the range can't be read.
+    ///
+    /// Only the chunks covering the range are fetched, from local storage or
+    /// else from their providers, along with the manifest nodes above them.
+    /// Content-defined chunks are found by the sizes recorded with them;
+    /// files uploaded before those were recorded are read from the start.
+    pub fn read_range(
+        &self,
+        metadata: FileInfo,
+        offset: u64,
+        length: u64,
+    ) -> mpsc::Receiver<Result<Vec<u8>, String>> {
+        let (tx, rx) = mpsc::channel(READ_QUEUE);
+        let node = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = node.send_range(&metadata, offset, length, &tx).await {
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+        rx
+    }
+
+    async fn send_range(
+        &self,
+        metadata: &FileInfo,
+        offset: u64,
+        length: u64,
+        tx: &mpsc::Sender<Result<Vec<u8>, String>>,
+    ) -> Result<(), String> {
+        if metadata.directory.is_some() {
+            return Err("cannot read a range of a directory".into());
+        }
+        let end = offset.saturating_add(length).min(metadata.size);
+        let load = |chunk_hash: Vec<u8>| async move { self.fetch_chunk(&chunk_hash).await };
+        let mut walker =
+            ManifestWalker::new(metadata.chunk_hashes.clone(), metadata.manifest_depth);
+
+        let Some(layout) = metadata.stripes else {
+            let mut position = match metadata.cdc {
+                Some(_) if metadata.chunk_sizes.len() == metadata.chunk_hashes.len() => {
+                    walker
+                        .seek_offset(&metadata.chunk_sizes, offset, load)
+                        .await?
+                }
+                Some(_) => 0,
+                // fixed-size chunks are found by index
+                None => {
+                    let first = offset / CHUNK_SIZE as u64;
+                    walker.seek(first as usize, FANOUT, load).await?;
+                    first * CHUNK_SIZE as u64
+                }
+            };
+            while position < end {
+                let chunk_hash = self.next_chunk(&mut walker).await?;
+                let chunk = self
+                    .fetch_chunk(&chunk_hash)
+                    .await
+                    .ok_or_else(|| format!("chunk {} is unavailable", hex::encode(&chunk_hash)))?;
+                if let Some(piece) = overlap(&chunk, position, offset, end) {
+                    if tx.send(Ok(piece.to_vec())).await.is_err() {
+                        // the reader went away
+                        return Ok(());
+                    }
+                }
+                position += chunk.len() as u64;
+            }
+            return Ok(());
+        };
+
+        let params = ErasureParams {
+            data_shards: layout.data_shards as usize,
+            parity_shards: layout.parity_shards as usize,
+        };
+        let stripe_len = params.data_shards + params.parity_shards;
+        let stripe_size = layout.shard_size * params.data_shards as u64;
+        let first = offset / stripe_size;
+        walker
+            .seek(first as usize * stripe_len, FANOUT, load)
+            .await?;
+        let mut position = first * stripe_size;
+        while position < end {
+            let mut stripe = Vec::with_capacity(stripe_len);
+            for _ in 0..stripe_len {
+                stripe.push(self.next_chunk(&mut walker).await?);
+            }
+            // the data shards covering the range, parity only to rebuild
+            // the ones that can't be fetched
+            let shard_start = |i: usize| position + i as u64 * layout.shard_size;
+            let wanted = |i: usize| shard_start(i) < end && shard_start(i + 1) > offset;
+            let mut shards: Vec<Option<Vec<u8>>> = vec![None; stripe_len];
+            for i in (0..params.data_shards).filter(|&i| wanted(i)) {
+                shards[i] = self.fetch_chunk(&stripe[i]).await;
+            }
+            if (0..params.data_shards).any(|i| wanted(i) && shards[i].is_none()) {
+                for (shard, shard_hash) in shards.iter_mut().zip(&stripe) {
+                    if shard.is_none() {
+                        *shard = self.fetch_chunk(shard_hash).await;
+                    }
+                }
+                erasure::reconstruct(&mut shards, params).map_err(|e| {
+                    format!("stripe {} can't be rebuilt: {}", position / stripe_size, e)
+                })?;
+            }
+            for (i, shard) in shards.iter().enumerate().take(params.data_shards) {
+                let Some(piece) = shard
+                    .as_ref()
+                    .and_then(|shard| overlap(shard, shard_start(i), offset, end))
+                else {
+                    continue;
+                };
+                if tx.send(Ok(piece.to_vec())).await.is_err() {
+                    return Ok(());
+                }
+            }
+            position += stripe_size;
+        }
+        Ok(())
+    }
+
+    async fn next_chunk(&self, walker: &mut ManifestWalker) -> Result<Vec<u8>, String> {
+        walker
+            .next(|chunk_hash| async move { self.fetch_chunk(&chunk_hash).await })
+            .await?
+            .ok_or_else(|| "file has fewer chunks than its size".to_string())
+    }
+
+    /// Returns a chunk from local storage, or else from the first of its
+    /// providers that serves it intact.
+    pub async fn fetch_chunk(&self, chunk_hash: &[u8]) -> Option<Vec<u8>> {
+        if let Some(data) = self.get_chunk(chunk_hash) {
+            if hash(&data) == chunk_hash {
+                return Some(data);
+            }
+        }
+        let key: [u8; 32] = chunk_hash.try_into().ok()?;
+        self.fetch_from_providers(&key, |mut client| async move {
+            let request = self.signed(
+                "GetChunk",
+                GetChunkRequest {
+                    chunk_hash: key.to_vec(),
+                },
+            );
+            Ok(client.get_chunk(request).await?.into_inner().chunk_data)
+        })
+        .await
+    }
+
+    /// Asks the providers of `key` in turn for the data stored under it and
+    /// returns the first that hashes to `key`. `fetch` signs its requests
+    /// like every other peer RPC.
+    async fn fetch_from_providers<F, Fut>(&self, key: &[u8; 32], fetch: F) -> Option<Vec<u8>>
+    where
+        F: Fn(PeerServiceClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<Vec<u8>, QueryError>>,
+    {
+        for record in self.find_value(key).await {
+            if record.node_id == self.id {
+                continue;
+            }
+            let request = async {
+                let client = PeerServiceClient::connect(record.address.clone()).await?;
+                fetch(client).await
+            };
+            match tokio::time::timeout(self.config.rpc_timeout, request).await {
+                Ok(Ok(data)) if hash(&data) == key => return Some(data),
+                Ok(Ok(_)) => log::warn!(
+                    "{} served corrupt data for {}",
+                    record.address,
+                    hex::encode(key)
+                ),
+                Ok(Err(e)) => log::info!("Failed to fetch from {}: {}", record.address, e),
+                Err(_) => log::info!("Timed out fetching from {}", record.address),
+            }
+        }
+        None
+    }
+}
+
+/// The part of a chunk starting at `position` in the file that falls within
+/// `offset..end`, if any.
+fn overlap(chunk: &[u8], position: u64, offset: u64, end: u64) -> Option<&[u8]> {
+    let len = chunk.len() as u64;
+    let start = offset.saturating_sub(position).min(len);
+    let stop = end.saturating_sub(position).min(len);
+    (start < stop).then(|| &chunk[start as usize..stop as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::CdcLayout;
+    use crate::testing::{connect, spawn_node, test_config};
+
+    #[test]
+    fn overlap_clips_chunks_to_the_range() {
+        let chunk = [0, 1, 2, 3, 4, 5, 6, 7];
+        // the chunk spans 8..16 of the file
+        assert_eq!(overlap(&chunk, 8, 0, 100), Some(&chunk[..]));
+        assert_eq!(overlap(&chunk, 8, 10, 12), Some(&chunk[2..4]));
+        assert_eq!(overlap(&chunk, 8, 14, 100), Some(&chunk[6..]));
+        assert_eq!(overlap(&chunk, 8, 0, 8), None);
+        assert_eq!(overlap(&chunk, 8, 16, 20), None);
+    }
+
+    #[tokio::test]
+    async fn content_defined_chunks_are_found_by_their_sizes() {
+        let holder = spawn_node(test_config()).await;
+        let reader = spawn_node(test_config()).await;
+        connect(&[&holder, &reader]).await;
+        let chunks: Vec<Vec<u8>> = [5, 9, 3, 7].iter().map(|&n| vec![n; n as usize]).collect();
+        let metadata = FileInfo {
+            name: "cdc.bin".to_string(),
+            size: 24,
+            chunk_hashes: chunks.iter().map(|chunk| hash(chunk)).collect(),
+            stripes: None,
+            cdc: Some(CdcLayout {
+                min_size: 1,
+                avg_size: 4,
+                max_size: 16,
+            }),
+            manifest_depth: 0,
+            directory: None,
+            chunk_sizes: vec![5, 9, 3, 7],
+        };
+        // only the chunks from byte 14 on exist anywhere
+        for chunk in &chunks[2..] {
+            let chunk_hash: [u8; 32] = hash(chunk).try_into().unwrap();
+            holder.store_chunk(&chunk_hash, chunk).await.unwrap();
+            holder.provide(&chunk_hash).await.unwrap();
+        }
+
+        let mut rx = reader.read_range(metadata, 15, 5);
+        let mut read = Vec::new();
+        while let Some(piece) = rx.recv().await {
+            read.extend(piece.unwrap());
+        }
+        assert_eq!(read, [3, 3, 7, 7, 7]);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::dht::Peer;
+use crate::node::Node;
+use crate::utils::{tick_or_shutdown, ticker, unix_time};
+use futures::future::join_all;
+use std::collections::HashSet;
+use tokio::sync::watch;
+
+impl Node {
+    /// Periodically re-announces the keys we provide ourselves, so our records
+    /// are refreshed before they expire and reach peers that joined since.
+    pub(crate) async fn run_republish(self, mut shutdown: watch::Receiver<bool>) {
+        let mut ticker = ticker(self.config.republish_interval);
+        while tick_or_shutdown(&mut ticker, &mut shutdown).await {
+            self.republish_records().await;
+        }
+    }
+
+    async fn republish_records(&self) {
+        let keys: Vec<[u8; 32]> = self
+            .storage
+            .provider_entries()
+            .into_iter()
+            .filter(|(_, records)| records.iter().any(|r| r.node_id == self.id))
+            .filter_map(|(key, _)| key.try_into().ok())
+            .collect();
+
+        for key in keys {
+            match self.provide(&key).await {
+                Ok(announced) => {
+                    log::info!("Republished {} to {} peers", hex::encode(key), announced)
+                }
+                Err(e) => log::warn!("Failed to republish {}: {}", hex::encode(key), e),
+            }
+        }
+    }
+
+    /// Periodically pushes the records we hold for other providers to peers
+    /// that have become one of the k closest to the key since the last round.
+    pub(crate) async fn run_replicate(self, mut shutdown: watch::Receiver<bool>) {
+        let mut ticker = ticker(self.config.replicate_interval);
+        while tick_or_shutdown(&mut ticker, &mut shutdown).await {
+            self.replicate_records().await;
+        }
+    }
+
+    async fn replicate_records(&self) {
+        let now = unix_time();
+        for (key, records) in self.storage.provider_entries() {
+            let records: Vec<_> = records
+                .into_iter()
+                .filter(|r| r.node_id != self.id && !r.is_expired(now))
+                .collect();
+            let Ok(target) = <[u8; 32]>::try_from(key.as_slice()) else {
+                continue;
+            };
+            if records.is_empty() {
+                continue;
+            }
+
+            let already = self
+                .replicated_to
+                .lock()
+                .await
+                .get(&key)
+                .cloned()
+                .unwrap_or_default();
+            let new_peers: Vec<Peer> = self
+                .find_node(&target)
+                .await
+                .into_iter()
+                .filter(|p| !already.contains(&p.node_id))
+                .collect();
+            if new_peers.is_empty() {
+                continue;
+            }
+
+            let mut reached = Vec::new();
+            for peer in &new_peers {
+                let results = join_all(
+                    records
+                        .iter()
+                        .map(|record| self.store_provider(peer, &target, record)),
+                )
+                .await;
+                if results.iter().all(|r| r.is_ok()) {
+                    reached.push(peer.node_id);
+                }
+            }
+            log::info!(
+                "Replicated {} records for {} to {} new peers",
+                records.len(),
+                hex::encode(&key),
+                reached.len()
+            );
+            self.replicated_to
+                .lock()
+                .await
+                .entry(key)
+                .or_default()
+                .extend(reached);
+        }
+    }
+
+    /// Periodically drops expired provider records.
+    pub(crate) async fn run_expiry(self, mut shutdown: watch::Receiver<bool>) {
+        let mut ticker = ticker(self.config.expiry_interval);
+        while tick_or_shutdown(&mut ticker, &mut shutdown).await {
+            self.expire_records().await;
+        }
+    }
+
+    async fn expire_records(&self) {
+        let expired = self
+            .write_storage(|storage| storage.expire_providers(unix_time()))
+            .await;
+        match expired {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Expired {} provider records", removed),
+            Err(e) => log::warn!("Failed to expire provider records: {}", e),
+        }
+
+        // forget where we replicated keys we no longer hold
+        let held: HashSet<Vec<u8>> = self
+            .storage
+            .provider_entries()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        self.replicated_to
+            .lock()
+            .await
+            .retain(|key, _| held.contains(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::ProviderRecord;
+    use crate::testing::{connect, spawn_node, test_config};
+    use crate::utils::unix_time;
+
+    fn record(node_id: u8, expires_at: u64) -> ProviderRecord {
+        ProviderRecord {
+            node_id: [node_id; 32],
+            address: format!("http://10.0.0.{}:7001", node_id),
+            timestamp: unix_time() - 10,
+            expires_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn expiry_drops_expired_records() {
+        let node = spawn_node(test_config()).await;
+        let now = unix_time();
+        node.storage
+            .add_provider(b"old", &record(1, now - 1))
+            .unwrap();
+        node.storage
+            .add_provider(b"new", &record(2, now + 60))
+            .unwrap();
+        node.replicated_to
+            .lock()
+            .await
+            .insert(b"old".to_vec(), Default::default());
+
+        node.expire_records().await;
+
+        assert!(node.storage.get_providers(b"old").is_empty());
+        assert_eq!(node.storage.get_providers(b"new").len(), 1);
+        assert!(!node
+            .replicated_to
+            .lock()
+            .await
+            .contains_key(&b"old".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn republish_refreshes_our_own_records() {
+        let node = spawn_node(test_config()).await;
+        let peer = spawn_node(test_config()).await;
+        connect(&[&node, &peer]).await;
+        let key = [9; 32];
+        let mut stale = node.own_record().unwrap();
+        stale.timestamp -= 100;
+        stale.expires_at = unix_time() + 5;
+        node.storage.add_provider(&key, &stale).unwrap();
+
+        node.republish_records().await;
+
+        let ttl = node.config.record_ttl.as_secs();
+        for holder in [&node, &peer] {
+            let records = holder.storage.get_providers(&key);
+            assert_eq!(records.len(), 1);
+            assert!(records[0].expires_at >= unix_time() + ttl - 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn replication_reaches_peers_that_become_closest() {
+        let node = spawn_node(test_config()).await;
+        let first = spawn_node(test_config()).await;
+        connect(&[&node, &first]).await;
+        let key = [9; 32];
+        let held = record(1, unix_time() + 60);
+        node.storage.add_provider(&key, &held).unwrap();
+
+        node.replicate_records().await;
+        assert_eq!(first.storage.get_providers(&key), vec![held.clone()]);
+
+        // a peer joining later gets the record on the next round, while the
+        // one that already has it isn't sent it again
+        let second = spawn_node(test_config()).await;
+        connect(&[&node, &second]).await;
+        first.storage.remove_provider(&key, &held.node_id).unwrap();
+        node.replicate_records().await;
+        assert_eq!(second.storage.get_providers(&key), vec![held]);
+        assert!(first.storage.get_providers(&key).is_empty());
+    }
+}
@@ -0,0 +1,488 @@
+use crate::dht::{xor_distance, Peer};
+use crate::erasure::{self, ErasureParams};
+use crate::lookup::QueryError;
+use crate::manifest::ManifestWalker;
+use crate::node::Node;
+use crate::replication::acknowledged;
+use crate::storage::FileInfo;
+use crate::storage_proto::peer_service_client::PeerServiceClient;
+use crate::storage_proto::HasChunksRequest;
+use crate::utils::{hash, tick_or_shutdown, ticker};
+use futures::future::join_all;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::watch;
+use tokio::time::error::Elapsed;
+
+// most chunk hashes sent in one HasChunks request
+const HAS_CHUNKS_BATCH: usize = 1024;
+
+/// Running totals of what the repair loop has done since the node started.
+#[derive(Default)]
+pub struct RepairMetrics {
+    pub rounds: AtomicU64,
+    pub chunks_checked: AtomicU64,
+    pub under_replicated: AtomicU64,
+    pub replicas_pushed: AtomicU64,
+    pub failed_pushes: AtomicU64,
+}
+
+/// An erasure-coded file whose metadata we hold, with its shards stripe by
+/// stripe.
+struct CodedFile {
+    file_hash: [u8; 32],
+    metadata: FileInfo,
+    encoded: Vec<u8>,
+    stripes: Vec<Vec<[u8; 32]>>,
+    // whether we are the closest node to the file hash that we know of, and
+    // so the one to rebuild its lost shards
+    repairer: bool,
+}
+
+impl Node {
+    /// Periodically makes sure every chunk we hold is still stored on at
+    /// least `replication_factor` of the k closest peers to its hash, pushing
+    /// copies to the closest ones without it when too few are left.
+    ///
+    /// The shards of erasure-coded files are left out: they only need one
+    /// copy each, and whichever node is closest to the file hash rebuilds
+    /// any shard that no peer holds anymore from the rest of its stripe.
+    pub(crate) async fn run_repair(self, mut shutdown: watch::Receiver<bool>) {
+        let mut ticker = ticker(self.config.repair_interval);
+        while tick_or_shutdown(&mut ticker, &mut shutdown).await {
+            self.repair_chunks().await;
+        }
+    }
+
+    async fn repair_chunks(&self) {
+        let metrics = &self.repair_metrics;
+        metrics.rounds.fetch_add(1, Ordering::Relaxed);
+
+        let coded = self.coded_files().await;
+        let shards: HashSet<[u8; 32]> = coded
+            .iter()
+            .flat_map(|file| file.stripes.iter().flatten())
+            .copied()
+            .collect();
+
+        // where each chunk and each shard we rebuild may live, according to
+        // our routing table
+        let (targets, shard_targets) = {
+            let routing_table = self.routing_table.lock().await;
+            let targets: Vec<([u8; 32], Vec<Peer>)> = self
+                .storage
+                .chunk_hashes()
+                .into_iter()
+                .filter_map(|hash| <[u8; 32]>::try_from(hash).ok())
+                .filter(|key| !shards.contains(key))
+                .map(|key| (key, routing_table.find_closest_peers(&key)))
+                .collect();
+            let shard_targets: HashMap<[u8; 32], Vec<Peer>> = coded
+                .iter()
+                .filter(|file| file.repairer)
+                .flat_map(|file| file.stripes.iter().flatten())
+                .map(|key| (*key, routing_table.find_closest_peers(key)))
+                .collect();
+            (targets, shard_targets)
+        };
+
+        // ask each peer about all of its chunks at once
+        let mut expected: HashMap<[u8; 32], (Peer, Vec<[u8; 32]>)> = HashMap::new();
+        let all_targets = targets.iter().map(|(key, peers)| (key, peers));
+        for (key, peers) in all_targets.chain(&shard_targets) {
+            for peer in peers {
+                expected
+                    .entry(peer.node_id)
+                    .or_insert_with(|| (peer.clone(), Vec::new()))
+                    .1
+                    .push(*key);
+            }
+        }
+        let results = join_all(
+            expected
+                .values()
+                .map(|(peer, keys)| self.has_chunks(peer, keys)),
+        )
+        .await;
+
+        let mut held = HashSet::new();
+        // peers whose answer we don't have this round, which neither count as
+        // holders nor get pushed to
+        let mut unknown = HashSet::new();
+        for ((peer, keys), result) in expected.values().zip(results) {
+            match result {
+                Ok(present) => held.extend(
+                    keys.iter()
+                        .zip(present)
+                        .filter(|(_, present)| *present)
+                        .map(|(key, _)| (peer.node_id, *key)),
+                ),
+                Err(e) => {
+                    log::info!("Peer {} failed chunk check: {}", peer.address, e);
+                    unknown.insert(peer.node_id);
+                    // a slow answer about many chunks doesn't mean the peer is
+                    // gone; one we can't reach at all is dropped from the
+                    // table, so the next round targets whoever replaces it
+                    if !e.is::<Elapsed>() {
+                        self.report_failure(peer).await;
+                    }
+                }
+            }
+        }
+
+        let (mut under_replicated, mut pushed) = (0, 0);
+        for (key, peers) in &targets {
+            metrics.chunks_checked.fetch_add(1, Ordering::Relaxed);
+            let holders = peers
+                .iter()
+                .filter(|p| held.contains(&(p.node_id, *key)))
+                .count();
+            let wanted = self.config.replication_factor.saturating_sub(holders);
+            let missing: Vec<Peer> = peers
+                .iter()
+                .filter(|p| !unknown.contains(&p.node_id))
+                .filter(|p| !held.contains(&(p.node_id, *key)))
+                .take(wanted)
+                .cloned()
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            let Some(data) = self.get_chunk(key) else {
+                continue;
+            };
+            under_replicated += 1;
+
+            let acks =
+                acknowledged(&missing, |peer| self.push_chunk(peer, key, data.clone())).await;
+            pushed += acks.len();
+            metrics
+                .failed_pushes
+                .fetch_add((missing.len() - acks.len()) as u64, Ordering::Relaxed);
+        }
+
+        for file in coded.iter().filter(|file| file.repairer) {
+            for stripe in &file.stripes {
+                metrics
+                    .chunks_checked
+                    .fetch_add(stripe.len() as u64, Ordering::Relaxed);
+                // a shard counts as lost once none of the peers it may be on
+                // has it, nor do we
+                let lost: Vec<usize> = (0..stripe.len())
+                    .filter(|&i| {
+                        let key = stripe[i];
+                        !self.storage.has_chunk(&key)
+                            && !shard_targets[&key].iter().any(|p| {
+                                held.contains(&(p.node_id, key)) || unknown.contains(&p.node_id)
+                            })
+                    })
+                    .collect();
+                if lost.is_empty() {
+                    continue;
+                }
+                under_replicated += lost.len() as u64;
+
+                let holders = stripe.iter().flat_map(|key| {
+                    shard_targets[key]
+                        .iter()
+                        .filter(|p| held.contains(&(p.node_id, *key)))
+                        .map(|p| p.node_id)
+                });
+                let mut used = holders.collect();
+                match self
+                    .rebuild_shards(file, stripe, &lost, &shard_targets, &mut used)
+                    .await
+                {
+                    Ok(placed) => {
+                        pushed += placed;
+                        metrics
+                            .failed_pushes
+                            .fetch_add((lost.len() - placed) as u64, Ordering::Relaxed);
+                    }
+                    Err(e) => log::warn!(
+                        "Failed to rebuild shards of {}: {}",
+                        hex::encode(file.file_hash),
+                        e
+                    ),
+                }
+            }
+        }
+
+        metrics
+            .under_replicated
+            .fetch_add(under_replicated, Ordering::Relaxed);
+        metrics
+            .replicas_pushed
+            .fetch_add(pushed as u64, Ordering::Relaxed);
+
+        if under_replicated > 0 {
+            log::info!(
+                "Repair: {} of {} chunks were under-replicated, pushed {} copies",
+                under_replicated,
+                targets.len() + shard_targets.len(),
+                pushed
+            );
+        }
+    }
+
+    /// The erasure-coded files whose metadata we hold. Manifest nodes we
+    /// don't have are fetched from the network to list the shards.
+    async fn coded_files(&self) -> Vec<CodedFile> {
+        let mut coded = Vec::new();
+        for (file_hash, metadata) in self.storage.metadata_entries() {
+            let Some(encoded) = self.get_metadata_bytes(&file_hash) else {
+                continue;
+            };
+            let (Ok(file_hash), Some(layout)) = (<[u8; 32]>::try_from(file_hash), metadata.stripes)
+            else {
+                continue;
+            };
+            let mut walker =
+                ManifestWalker::new(metadata.chunk_hashes.clone(), metadata.manifest_depth);
+            let mut shards = Vec::new();
+            let listed = loop {
+                let next = walker
+                    .next(|hash| async move { self.fetch_chunk(&hash).await })
+                    .await;
+                match next {
+                    Ok(Some(shard)) => match <[u8; 32]>::try_from(shard) {
+                        Ok(key) => shards.push(key),
+                        Err(_) => break Err("malformed shard hash".to_string()),
+                    },
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+            if let Err(e) = listed {
+                log::warn!("Can't list the shards of {}: {}", hex::encode(file_hash), e);
+                continue;
+            }
+
+            let stripe_len = (layout.data_shards + layout.parity_shards) as usize;
+            let own_distance = xor_distance(&self.id, &file_hash);
+            let repairer = self
+                .routing_table
+                .lock()
+                .await
+                .find_closest_peers(&file_hash)
+                .first()
+                .is_none_or(|p| xor_distance(&p.node_id, &file_hash) > own_distance);
+            coded.push(CodedFile {
+                file_hash,
+                metadata,
+                encoded,
+                stripes: shards.chunks(stripe_len).map(<[_]>::to_vec).collect(),
+                repairer,
+            });
+        }
+        coded
+    }
+
+    /// Rebuilds the `lost` shards of a stripe from any k of the others and
+    /// stores each on a peer not in `used`. Returns how many were placed.
+    async fn rebuild_shards(
+        &self,
+        file: &CodedFile,
+        stripe: &[[u8; 32]],
+        lost: &[usize],
+        closest: &HashMap<[u8; 32], Vec<Peer>>,
+        used: &mut HashSet<[u8; 32]>,
+    ) -> Result<usize, String> {
+        let layout = file.metadata.stripes.ok_or("file is not erasure-coded")?;
+        let params = ErasureParams {
+            data_shards: layout.data_shards as usize,
+            parity_shards: layout.parity_shards as usize,
+        };
+        let mut shards: Vec<Option<Vec<u8>>> = vec![None; stripe.len()];
+        let mut present = 0;
+        for (i, key) in stripe.iter().enumerate() {
+            if present == params.data_shards {
+                break;
+            }
+            if !lost.contains(&i) {
+                shards[i] = self.fetch_chunk(key).await;
+                present += usize::from(shards[i].is_some());
+            }
+        }
+        erasure::rebuild(&mut shards, params).map_err(|e| e.to_string())?;
+
+        let mut placed = 0;
+        for &i in lost {
+            let key = &stripe[i];
+            let data = shards[i].take().unwrap_or_default();
+            if hash(&data) != key {
+                return Err(format!("rebuilt shard {} doesn't match", hex::encode(key)));
+            }
+            let holder = self
+                .place_shard(
+                    &file.file_hash,
+                    &file.encoded,
+                    key,
+                    data,
+                    &closest[key],
+                    used,
+                )
+                .await;
+            placed += usize::from(holder.is_some());
+        }
+        Ok(placed)
+    }
+
+    /// Asks `peer` which of `keys` it holds, in batches. Connecting and each
+    /// batch get the RPC timeout of their own; running out of it fails with
+    /// [`Elapsed`].
+    async fn has_chunks(&self, peer: &Peer, keys: &[[u8; 32]]) -> Result<Vec<bool>, QueryError> {
+        let timeout = self.config.rpc_timeout;
+        let mut client =
+            tokio::time::timeout(timeout, PeerServiceClient::connect(peer.address.clone()))
+                .await??;
+        let mut present = Vec::with_capacity(keys.len());
+        for batch in keys.chunks(HAS_CHUNKS_BATCH) {
+            let request = client.has_chunks(self.signed(
+                "HasChunks",
+                HasChunksRequest {
+                    chunk_hashes: batch.iter().map(|k| k.to_vec()).collect(),
+                },
+            ));
+            let response = tokio::time::timeout(timeout, request).await??.into_inner();
+            if response.present.len() != batch.len() {
+                return Err("peer answered for the wrong number of chunks".into());
+            }
+            present.extend(response.present);
+        }
+        Ok(present)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dht::xor_distance;
+    use crate::erasure::{self, ErasureParams};
+    use crate::node::{Node, NodeConfig};
+    use crate::storage::{FileInfo, StripeLayout};
+    use crate::testing::{connect, spawn_node, test_config};
+    use crate::utils::hash;
+    use std::collections::HashSet;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn holders_among_the_closest_peers_count_and_lost_copies_are_pushed() {
+        let config = NodeConfig {
+            replication_factor: 2,
+            ..test_config()
+        };
+        let node = spawn_node(config.clone()).await;
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            peers.push(spawn_node(config.clone()).await);
+        }
+        connect(&[&node, &peers[0], &peers[1], &peers[2]]).await;
+
+        let data = b"repair me".to_vec();
+        let key: [u8; 32] = hash(&data).try_into().unwrap();
+        peers.sort_by_key(|p| xor_distance(&p.id, &key));
+        node.store_chunk(&key, &data).await.unwrap();
+        // the closest peer doesn't have it, but two others among the closest do
+        peers[1].store_chunk(&key, &data).await.unwrap();
+        peers[2].store_chunk(&key, &data).await.unwrap();
+
+        node.repair_chunks().await;
+        let pushed = || node.repair_metrics.replicas_pushed.load(Ordering::Relaxed);
+        assert_eq!(pushed(), 0);
+
+        // one holder loses its copy, so the closest peer without it gets one
+        peers[2].storage.delete_chunk(&key).unwrap();
+        node.repair_chunks().await;
+        assert_eq!(pushed(), 1);
+        assert!(peers[0].storage.has_chunk(&key));
+        assert!(!peers[2].storage.has_chunk(&key));
+    }
+
+    #[tokio::test]
+    async fn shards_get_one_copy_each_and_lost_ones_are_rebuilt() {
+        let mut nodes = Vec::new();
+        for _ in 0..5 {
+            nodes.push(spawn_node(test_config()).await);
+        }
+        connect(&nodes.iter().collect::<Vec<_>>()).await;
+        let uploader = &nodes[0];
+
+        let params = ErasureParams {
+            data_shards: 2,
+            parity_shards: 1,
+        };
+        // three stripes of random bytes, so no two shards are the same
+        let data: Vec<u8> = (0u8..2).flat_map(|i| hash(&[i])).take(48).collect();
+        let shards = erasure::encode(&data, 8, params).unwrap();
+        let metadata = FileInfo {
+            name: "coded.bin".to_string(),
+            size: 48,
+            chunk_hashes: shards.iter().map(|shard| hash(shard)).collect(),
+            stripes: Some(StripeLayout {
+                data_shards: 2,
+                parity_shards: 1,
+                shard_size: 8,
+            }),
+            cdc: None,
+            manifest_depth: 0,
+            directory: None,
+            chunk_sizes: Vec::new(),
+        };
+        let file_hash: [u8; 32] = hash(&metadata.encode()).try_into().unwrap();
+        uploader
+            .store_metadata(&file_hash, &metadata.encode())
+            .await
+            .unwrap();
+        for shard in &shards {
+            uploader.store_chunk(&hash(shard), shard).await.unwrap();
+        }
+
+        let report = uploader.replicate_file(&file_hash).await.unwrap();
+        assert_eq!((report.chunks, report.shards), (9, 9));
+        assert_eq!(report.under_replicated, 0);
+        let holders = |key: &[u8]| -> Vec<[u8; 32]> {
+            nodes[1..]
+                .iter()
+                .filter(|n| n.storage.has_chunk(key))
+                .map(|n| n.id)
+                .collect()
+        };
+        for stripe in metadata.chunk_hashes.chunks(3) {
+            let stripe_holders: HashSet<[u8; 32]> = stripe
+                .iter()
+                .map(|key| {
+                    let holders = holders(key);
+                    assert_eq!(holders.len(), 1);
+                    holders[0]
+                })
+                .collect();
+            assert_eq!(stripe_holders.len(), 3);
+        }
+
+        // a shard is lost everywhere; only the node closest to the file hash
+        // rebuilds it, and nobody copies the surviving shards around
+        let lost = &metadata.chunk_hashes[4];
+        for node in &nodes {
+            node.storage.delete_chunk(lost).unwrap();
+        }
+        for node in &nodes {
+            node.repair_chunks().await;
+        }
+        let copies = |key: &[u8]| nodes.iter().filter(|n| n.storage.has_chunk(key)).count();
+        assert_eq!(copies(lost), 1);
+        for key in metadata.chunk_hashes.iter().filter(|key| *key != lost) {
+            assert_eq!(copies(key), 2);
+        }
+        let repairer: &Node = nodes
+            .iter()
+            .min_by_key(|n| xor_distance(&n.id, &file_hash))
+            .unwrap();
+        assert_eq!(
+            repairer
+                .repair_metrics
+                .replicas_pushed
+                .load(Ordering::Relaxed),
+            1
+        );
+    }
+}
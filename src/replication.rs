@@ -0,0 +1,428 @@
+use crate::dht::Peer;
+use crate::lookup::QueryError;
+use crate::manifest::ManifestWalker;
+use crate::node::Node;
+use crate::storage::ProviderRecord;
+use crate::storage_proto::peer_service_client::PeerServiceClient;
+use crate::storage_proto::{StoreChunkRequest, StoreMetadataRequest};
+use crate::utils::unix_time;
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashSet;
+use std::future::Future;
+
+// how many chunks, or stripes of shards, are replicated at once
+const REPLICATION_CONCURRENCY: usize = 16;
+// how many under-replicated chunks a report lists by hash
+pub const MAX_REPORTED_CHUNKS: usize = 100;
+
+/// A chunk with how many copies it was meant to get and the peers that
+/// acknowledged one.
+pub type ChunkAcks = (Vec<u8>, usize, Vec<Peer>);
+
+/// How the replicas pushed for a file fared. Only the chunks that fell
+/// short are listed, so the report stays small however large the file.
+#[derive(Default)]
+pub struct ReplicationReport {
+    /// The peers that acknowledged the metadata.
+    pub metadata: Vec<Peer>,
+    /// How many chunks were pushed, manifest nodes and shards included.
+    pub chunks: usize,
+    /// How many of them are shards, meant for a single peer each.
+    pub shards: usize,
+    /// How many of them reached fewer peers than they were meant for.
+    pub under_replicated: usize,
+    /// The first `MAX_REPORTED_CHUNKS` of those.
+    pub reported: Vec<ChunkAcks>,
+}
+
+impl ReplicationReport {
+    fn add(&mut self, acks: ChunkAcks) {
+        self.chunks += 1;
+        if acks.2.len() < acks.1 {
+            self.under_replicated += 1;
+            if self.reported.len() < MAX_REPORTED_CHUNKS {
+                self.reported.push(acks);
+            }
+        }
+    }
+}
+
+/// The replication work for one chunk, or for the shards of one stripe,
+/// which are placed together.
+enum Replica {
+    Copies(Vec<u8>),
+    Stripe(Vec<Vec<u8>>),
+}
+
+impl Node {
+    /// Copies a locally stored file to the network: its metadata goes to the
+    /// peers closest to the file hash and every chunk, manifest nodes
+    /// included, to the peers closest to the chunk hash,
+    /// `replication_factor` of each. The shards of an erasure-coded file are
+    /// the exception: each gets a single copy, on a peer holding no other
+    /// shard of its stripe, since the parity already covers its loss.
+    /// Replicas provide what they hold, so downloads still work once this
+    /// node is gone.
+    ///
+    /// Up to `REPLICATION_CONCURRENCY` chunks or stripes are looked up and
+    /// pushed at a time.
+    pub async fn replicate_file(
+        &self,
+        file_hash: &[u8; 32],
+    ) -> Result<ReplicationReport, QueryError> {
+        let (Some(metadata), Some(encoded)) = (
+            self.get_metadata(file_hash),
+            self.get_metadata_bytes(file_hash),
+        ) else {
+            return Err("file is not stored on this node".into());
+        };
+
+        let closest = self.find_node(file_hash).await;
+        let metadata_acks = acknowledged(self.replica_peers(&closest), |peer| {
+            self.push_metadata(peer, file_hash, encoded.clone())
+        })
+        .await;
+        self.announce_replicas(file_hash, &metadata_acks, &closest)
+            .await;
+
+        // the leaves are walked one at a time, never all held at once, and
+        // manifest nodes are pushed as the walk loads them like any other
+        // chunk
+        let stripe_len = metadata
+            .stripes
+            .map(|layout| (layout.data_shards + layout.parity_shards) as usize);
+        let mut walker =
+            ManifestWalker::new(metadata.chunk_hashes.clone(), metadata.manifest_depth);
+        let mut stripe = Vec::new();
+        let mut report = ReplicationReport {
+            metadata: metadata_acks,
+            ..ReplicationReport::default()
+        };
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            let mut nodes = Vec::new();
+            let leaf = walker
+                .next(|hash| {
+                    let node = self.get_chunk(&hash);
+                    nodes.push(hash);
+                    std::future::ready(node)
+                })
+                .await?;
+            let done = leaf.is_none();
+            let mut replicas: Vec<Replica> = nodes.into_iter().map(Replica::Copies).collect();
+            match (leaf, stripe_len) {
+                (Some(chunk_hash), None) => replicas.push(Replica::Copies(chunk_hash)),
+                (Some(shard_hash), Some(stripe_len)) => {
+                    stripe.push(shard_hash);
+                    if stripe.len() == stripe_len {
+                        replicas.push(Replica::Stripe(std::mem::take(&mut stripe)));
+                    }
+                }
+                (None, _) if !stripe.is_empty() => {
+                    replicas.push(Replica::Stripe(std::mem::take(&mut stripe)));
+                }
+                (None, _) => {}
+            }
+            for replica in replicas {
+                if let Replica::Stripe(shards) = &replica {
+                    report.shards += shards.len();
+                }
+                in_flight.push(self.replicate(file_hash, &encoded, replica));
+                if in_flight.len() == REPLICATION_CONCURRENCY {
+                    if let Some(acks) = in_flight.next().await {
+                        acks?.into_iter().for_each(|acks| report.add(acks));
+                    }
+                }
+            }
+            if done {
+                break;
+            }
+        }
+        while let Some(acks) = in_flight.next().await {
+            acks?.into_iter().for_each(|acks| report.add(acks));
+        }
+        Ok(report)
+    }
+
+    async fn replicate(
+        &self,
+        file_hash: &[u8; 32],
+        metadata: &[u8],
+        replica: Replica,
+    ) -> Result<Vec<ChunkAcks>, QueryError> {
+        match replica {
+            Replica::Copies(chunk_hash) => Ok(vec![self.replicate_chunk(chunk_hash).await?]),
+            Replica::Stripe(shards) => self.replicate_stripe(file_hash, metadata, shards).await,
+        }
+    }
+
+    /// Pushes a chunk to the `replication_factor` peers closest to it.
+    /// Returns it with that target and the peers that took a copy.
+    async fn replicate_chunk(&self, chunk_hash: Vec<u8>) -> Result<ChunkAcks, QueryError> {
+        let (key, data) = self.local_chunk(&chunk_hash)?;
+        let closest = self.find_node(&key).await;
+        let acks = acknowledged(self.replica_peers(&closest), |peer| {
+            self.push_chunk(peer, &key, data.clone())
+        })
+        .await;
+        self.announce_replicas(&key, &acks, &closest).await;
+        Ok((chunk_hash, self.config.replication_factor, acks))
+    }
+
+    /// Places the shards of one stripe with `place_shard`, each on its own
+    /// peer where there are enough of them.
+    async fn replicate_stripe(
+        &self,
+        file_hash: &[u8; 32],
+        metadata: &[u8],
+        shards: Vec<Vec<u8>>,
+    ) -> Result<Vec<ChunkAcks>, QueryError> {
+        let mut used = HashSet::new();
+        let mut acks = Vec::with_capacity(shards.len());
+        for shard_hash in shards {
+            let (key, data) = self.local_chunk(&shard_hash)?;
+            let closest = self.find_node(&key).await;
+            let holder = self
+                .place_shard(file_hash, metadata, &key, data, &closest, &mut used)
+                .await;
+            acks.push((shard_hash, 1, holder.into_iter().collect()));
+        }
+        Ok(acks)
+    }
+
+    /// Reads a chunk of a file we are replicating from local storage.
+    fn local_chunk(&self, chunk_hash: &[u8]) -> Result<([u8; 32], Vec<u8>), QueryError> {
+        let key: [u8; 32] = chunk_hash
+            .try_into()
+            .map_err(|_| "file metadata has a malformed chunk hash")?;
+        let data = self
+            .get_chunk(chunk_hash)
+            .ok_or_else(|| format!("chunk {} is not stored on this node", hex::encode(key)))?;
+        Ok((key, data))
+    }
+
+    /// Stores one shard of a stripe on the closest peer to it that isn't in
+    /// `used`, the peers holding the stripe's other shards, and adds that
+    /// peer to them. Only when every peer already holds one does a peer get
+    /// a second shard of the stripe. The peer is sent the file's encoded
+    /// `metadata` as well, which tells its repair rounds that the chunk is a
+    /// shard to leave alone. Returns the peer, unless none of them took the
+    /// shard.
+    pub(crate) async fn place_shard(
+        &self,
+        file_hash: &[u8; 32],
+        metadata: &[u8],
+        key: &[u8; 32],
+        data: Vec<u8>,
+        closest: &[Peer],
+        used: &mut HashSet<[u8; 32]>,
+    ) -> Option<Peer> {
+        let (fresh, reused): (Vec<&Peer>, Vec<&Peer>) =
+            closest.iter().partition(|p| !used.contains(&p.node_id));
+        for peer in fresh.into_iter().chain(reused) {
+            let pushed = async {
+                self.push_metadata(peer, file_hash, metadata.to_vec())
+                    .await?;
+                self.push_chunk(peer, key, data.clone()).await
+            };
+            match pushed.await {
+                Ok(()) => {
+                    used.insert(peer.node_id);
+                    let holder = std::slice::from_ref(peer);
+                    self.announce_replicas(key, holder, closest).await;
+                    return Some(peer.clone());
+                }
+                Err(e) => log::warn!("Failed to store shard on {}: {}", peer.address, e),
+            }
+        }
+        None
+    }
+
+    /// Keeps a chunk replica pushed by a peer and lists us as its provider.
+    pub async fn accept_chunk_replica(&self, hash: &[u8], data: &[u8]) -> std::io::Result<()> {
+        self.store_chunk(hash, data).await?;
+        self.provide_locally(hash).await
+    }
+
+    /// Keeps a file metadata replica pushed by a peer and lists us as its
+    /// provider.
+    pub async fn accept_metadata_replica(
+        &self,
+        file_hash: &[u8],
+        encoded: &[u8],
+    ) -> std::io::Result<()> {
+        self.store_metadata(file_hash, encoded).await?;
+        self.provide_locally(file_hash).await
+    }
+
+    /// Records ourselves as a provider of `key` without announcing it. We are
+    /// among the closest peers to the key, so lookups for it end here anyway;
+    /// republishing announces it to the others later.
+    async fn provide_locally(&self, key: &[u8]) -> std::io::Result<()> {
+        match self.own_record() {
+            Some(record) => self.add_provider(key, &record).await,
+            None => Ok(()),
+        }
+    }
+
+    /// The peers a key's replicas belong on, out of the closest ones.
+    fn replica_peers<'a>(&self, closest: &'a [Peer]) -> &'a [Peer] {
+        &closest[..closest.len().min(self.config.replication_factor)]
+    }
+
+    /// Stores provider records for the peers now holding `key` on the
+    /// closest peers to it, so lookups find the replicas even where the
+    /// uploader's own record is all a peer knows about.
+    async fn announce_replicas(&self, key: &[u8; 32], holders: &[Peer], closest: &[Peer]) {
+        let now = unix_time();
+        let records: Vec<ProviderRecord> = holders
+            .iter()
+            .map(|peer| ProviderRecord {
+                node_id: peer.node_id,
+                address: peer.address.clone(),
+                timestamp: now,
+                expires_at: now + self.config.record_ttl.as_secs(),
+            })
+            .collect();
+        for record in &records {
+            if let Err(e) = self.add_provider(key, record).await {
+                log::warn!("Failed to store provider record: {}", e);
+            }
+        }
+        let stores = closest
+            .iter()
+            .flat_map(|peer| records.iter().map(move |record| (peer, record)))
+            .map(|(peer, record)| self.store_provider(peer, key, record));
+        for result in join_all(stores).await {
+            if let Err(e) = result {
+                log::info!("Failed to announce replica: {}", e);
+            }
+        }
+    }
+
+    pub(crate) async fn push_chunk(
+        &self,
+        peer: &Peer,
+        hash: &[u8; 32],
+        data: Vec<u8>,
+    ) -> Result<(), QueryError> {
+        let request = async {
+            let mut client = PeerServiceClient::connect(peer.address.clone()).await?;
+            client
+                .store_chunk(self.signed(
+                    "StoreChunk",
+                    StoreChunkRequest {
+                        chunk_hash: hash.to_vec(),
+                        chunk_data: data,
+                    },
+                ))
+                .await?;
+            Ok::<_, QueryError>(())
+        };
+        tokio::time::timeout(self.config.rpc_timeout, request).await?
+    }
+
+    async fn push_metadata(
+        &self,
+        peer: &Peer,
+        file_hash: &[u8; 32],
+        encoded: Vec<u8>,
+    ) -> Result<(), QueryError> {
+        let request = async {
+            let mut client = PeerServiceClient::connect(peer.address.clone()).await?;
+            client
+                .store_metadata(self.signed(
+                    "StoreMetadata",
+                    StoreMetadataRequest {
+                        file_hash: file_hash.to_vec(),
+                        metadata: None,
+                        encoded,
+                    },
+                ))
+                .await?;
+            Ok::<_, QueryError>(())
+        };
+        tokio::time::timeout(self.config.rpc_timeout, request).await?
+    }
+}
+
+/// Runs `push` against every peer at once and returns the ones that
+/// acknowledged.
+pub(crate) async fn acknowledged<'a, F, Fut>(peers: &'a [Peer], push: F) -> Vec<Peer>
+where
+    F: Fn(&'a Peer) -> Fut,
+    Fut: Future<Output = Result<(), QueryError>>,
+{
+    let results = join_all(peers.iter().map(push)).await;
+    peers
+        .iter()
+        .zip(results)
+        .filter_map(|(peer, result)| match result {
+            Ok(()) => Some(peer.clone()),
+            Err(e) => {
+                log::warn!("Failed to replicate to {}: {}", peer.address, e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ManifestBuilder;
+    use crate::storage::FileInfo;
+    use crate::testing::{connect, spawn_node, test_config};
+    use crate::utils::hash;
+
+    #[tokio::test]
+    async fn manifest_trees_are_replicated_as_they_are_walked() {
+        let uploader = spawn_node(test_config()).await;
+        let a = spawn_node(test_config()).await;
+        let b = spawn_node(test_config()).await;
+        connect(&[&uploader, &a, &b]).await;
+
+        // a small fanout gives a tree two levels of manifest nodes deep
+        let mut manifest = ManifestBuilder::new(4);
+        let mut nodes = Vec::new();
+        for i in 0..40u32 {
+            let chunk = i.to_le_bytes();
+            uploader.store_chunk(&hash(&chunk), &chunk).await.unwrap();
+            nodes.extend(manifest.push(hash(&chunk), chunk.len()));
+        }
+        let top = manifest.finish();
+        nodes.extend(top.nodes);
+        for node in &nodes {
+            uploader.store_chunk(&hash(node), node).await.unwrap();
+        }
+        let metadata = FileInfo {
+            name: "large.bin".to_string(),
+            size: 4 * 40,
+            chunk_hashes: top.chunk_hashes,
+            stripes: None,
+            cdc: None,
+            manifest_depth: top.depth,
+            directory: None,
+            chunk_sizes: Vec::new(),
+        };
+        let file_hash: [u8; 32] = hash(&metadata.encode()).try_into().unwrap();
+        uploader
+            .store_metadata(&file_hash, &metadata.encode())
+            .await
+            .unwrap();
+
+        // two peers can't hold three copies, so every chunk falls short
+        let report = uploader.replicate_file(&file_hash).await.unwrap();
+        assert_eq!(top.depth, 2);
+        assert_eq!(report.chunks, 40 + nodes.len());
+        assert_eq!(report.under_replicated, report.chunks);
+        assert_eq!(
+            report.reported.len(),
+            report.chunks.min(MAX_REPORTED_CHUNKS)
+        );
+        for node in [&a, &b] {
+            assert_eq!(node.storage.chunk_hashes().len(), report.chunks);
+        }
+    }
+}
@@ -1,32 +1,83 @@
+use crate::auth::{verify_sender, Authenticator};
+use crate::chunking::CHUNK_SIZE;
 use crate::dht::Peer;
-use crate::node::Node;
+use crate::identity::{node_id_for, Identity};
+use crate::manifest::{self, ManifestBuilder, ManifestWalker};
+use crate::node::{Node, NodeConfig};
+use crate::storage::{open_backend, ProviderRecord};
 use crate::storage_proto::{
     peer_service_server::{PeerService, PeerServiceServer},
-    FindNodeRequest, FindNodeResponse, FindValueRequest, FindValueResponse, GetChunkRequest,
-    GetChunkResponse, GetFileMetadataRequest, GetFileMetadataResponse, InitiateUploadRequest,
-    InitiateUploadResponse, PeerMessage, PingRequest, PongResponse, StoreRequest, StoreResponse,
-    UploadChunkRequest, UploadChunkResponse,
+    FindNodeRequest, FindNodeResponse, FindProvidersRequest, FindProvidersResponse,
+    FindValueRequest, FindValueResponse, GetChunkRequest, GetChunkResponse, GetChunksRequest,
+    GetChunksResponse, GetFileMetadataRequest, GetFileMetadataResponse, GetRepairStatsRequest,
+    GetRepairStatsResponse, HasChunksRequest, HasChunksResponse, InitiateUploadRequest,
+    InitiateUploadResponse, PeerMessage, PingRequest, PongResponse, ProvideRequest,
+    ProvideResponse, ProviderList, ReadRangeRequest, ReadRangeResponse, ReplicaAcks,
+    ReplicateFileRequest, ReplicateFileResponse, StoreChunkRequest, StoreChunkResponse,
+    StoreMetadataRequest, StoreMetadataResponse, StoreRequest, StoreResponse, UploadChunkRequest,
+    UploadChunkResponse, UploadChunksResponse,
 };
-use crate::storage_proto::{ShowChunksRequest, ShowChunksResponse};
+use crate::storage_proto::{ChunkStatus, ShowChunksRequest, ShowChunksResponse};
+use crate::utils::{check_peer_address, hash};
+use crate::ServerArgs;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::routing::get;
+use axum::Router;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tonic::{transport::Server, Request, Response, Status};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{transport::Server, Code, Request, Response, Status, Streaming};
+
+// chunks read ahead of what a GetChunks client has received
+const GET_CHUNKS_QUEUE: usize = 4;
 
 pub struct PeerServer {
     node: Arc<Node>,
 }
 
+impl PeerServer {
+    pub fn new(node: Arc<Node>) -> Self {
+        Self { node }
+    }
+}
+
 #[tonic::async_trait]
 impl PeerService for PeerServer {
+    /// Answers a PING and adds the sender to the routing table, provided the
+    /// request is signed by the key of the peer it introduces. The reply tells
+    /// the sender where its request came from, so it can learn its address.
     async fn ping(&self, request: Request<PingRequest>) -> Result<Response<PongResponse>, Status> {
-        let remote_peer = request.into_inner().peer.unwrap();
-        let peer = Peer {
-            node_id: remote_peer.node_id.try_into().unwrap(),
-            address: remote_peer.address,
-        };
-        let node = self.node.clone();
-        node.routing_table.lock().await.add_peer(peer).await;
+        let signer = verify_sender(&request, "Ping")?;
+        let observed_address = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+
+        let remote_peer = request
+            .into_inner()
+            .peer
+            .ok_or_else(|| Status::invalid_argument("missing peer"))?;
+        // a peer that doesn't know its address yet can't be routed to
+        if !remote_peer.address.is_empty() {
+            let peer = Peer::try_from(remote_peer)?;
+            if peer.node_id != signer.node_id {
+                return Err(Status::unauthenticated(
+                    "request is not signed by the peer it introduces",
+                ));
+            }
+            self.node.add_peer(peer).await;
+        }
         let response = PongResponse {
             node_id: self.node.id.to_vec(),
+            public_key: self.node.identity.public_key().to_vec(),
+            observed_address,
         };
         Ok(Response::new(response))
     }
@@ -35,8 +86,16 @@ impl PeerService for PeerServer {
         &self,
         request: Request<StoreRequest>,
     ) -> Result<Response<StoreResponse>, Status> {
+        verify_sender(&request, "Store")?;
         let req = request.into_inner();
-        self.node.storage.store_value(&req.key, &req.value);
+        let record = req
+            .provider
+            .ok_or_else(|| Status::invalid_argument("missing provider"))
+            .and_then(ProviderRecord::try_from)?;
+        self.node
+            .accept_provider(&req.key, record)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to store provider: {}", e)))?;
         Ok(Response::new(StoreResponse { success: true }))
     }
 
@@ -44,8 +103,8 @@ impl PeerService for PeerServer {
         &self,
         request: Request<FindNodeRequest>,
     ) -> Result<Response<FindNodeResponse>, Status> {
-        let req = request.into_inner();
-        let target_id: [u8; 32] = req.target_id.try_into().unwrap();
+        verify_sender(&request, "FindNode")?;
+        let target_id = parse_key(request.into_inner().target_id)?;
         let peers = self
             .node
             .routing_table
@@ -62,17 +121,21 @@ impl PeerService for PeerServer {
         &self,
         request: Request<FindValueRequest>,
     ) -> Result<Response<FindValueResponse>, Status> {
+        verify_sender(&request, "FindValue")?;
         let req = request.into_inner();
         let key = req.key;
 
-        if let Some(value) = self.node.storage.get_value(&key) {
+        let providers = self.node.local_providers(&key);
+        if !providers.is_empty() {
             Ok(Response::new(FindValueResponse {
-                result: Some(crate::storage_proto::find_value_response::Result::Value(
-                    value,
-                )),
+                result: Some(
+                    crate::storage_proto::find_value_response::Result::Providers(ProviderList {
+                        providers: providers.into_iter().map(Into::into).collect(),
+                    }),
+                ),
             }))
         } else {
-            let target_id: [u8; 32] = key.try_into().unwrap();
+            let target_id = parse_key(key)?;
             let peers = self
                 .node
                 .routing_table
@@ -92,6 +155,38 @@ impl PeerService for PeerServer {
         }
     }
 
+    /// Announces this node as a provider of a key.
+    async fn provide(
+        &self,
+        request: Request<ProvideRequest>,
+    ) -> Result<Response<ProvideResponse>, Status> {
+        let key = parse_key(request.into_inner().key)?;
+        log::info!("Announcing ourselves as provider of {}", hex::encode(key));
+
+        let announced = self
+            .node
+            .provide(&key)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to provide key: {}", e)))?;
+        Ok(Response::new(ProvideResponse {
+            announced: announced as u32,
+        }))
+    }
+
+    /// Runs a network-wide lookup for the providers of a key.
+    async fn find_providers(
+        &self,
+        request: Request<FindProvidersRequest>,
+    ) -> Result<Response<FindProvidersResponse>, Status> {
+        let key = parse_key(request.into_inner().key)?;
+        log::info!("Looking up providers of {}", hex::encode(key));
+
+        let providers = self.node.find_value(&key).await;
+        Ok(Response::new(FindProvidersResponse {
+            providers: providers.into_iter().map(Into::into).collect(),
+        }))
+    }
+
     /// Retrieves a file chunk from local storage.
     async fn get_chunk(
         &self,
@@ -100,10 +195,101 @@ impl PeerService for PeerServer {
         let chunk_hash = request.into_inner().chunk_hash;
         log::info!("Received request for chunk {}", hex::encode(&chunk_hash));
 
-        match self.node.get_chunk(&chunk_hash) {
-            Some(chunk_data) => Ok(Response::new(GetChunkResponse { chunk_data })),
-            None => Err(Status::not_found("Chunk not found")),
+        let chunk_data = load_chunk(&self.node, &chunk_hash).await?;
+        Ok(Response::new(GetChunkResponse { chunk_data }))
+    }
+
+    type GetChunksStream = ReceiverStream<Result<GetChunksResponse, Status>>;
+
+    /// Streams chunks from local storage, in the order they were asked for.
+    async fn get_chunks(
+        &self,
+        request: Request<GetChunksRequest>,
+    ) -> Result<Response<Self::GetChunksStream>, Status> {
+        let req = request.into_inner();
+        // a file's chunks are found by walking its manifest tree as they go
+        let mut walker = if req.file_hash.is_empty() {
+            log::info!("Streaming {} chunks", req.chunk_hashes.len());
+            ManifestWalker::new(req.chunk_hashes, 0)
+        } else {
+            let metadata = self
+                .node
+                .get_metadata(&req.file_hash)
+                .ok_or_else(|| Status::not_found("File not found"))?;
+            log::info!(
+                "Streaming the chunks of file {}",
+                hex::encode(&req.file_hash)
+            );
+            ManifestWalker::new(metadata.chunk_hashes, metadata.manifest_depth)
+        };
+
+        // chunks are read as the client takes them, a few at a time
+        let (tx, rx) = mpsc::channel(GET_CHUNKS_QUEUE);
+        let node = self.node.clone();
+        tokio::spawn(async move {
+            loop {
+                let next = walker
+                    .next(|hash| std::future::ready(node.get_chunk(&hash)))
+                    .await;
+                let chunk_hash = match next {
+                    Ok(Some(chunk_hash)) => chunk_hash,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(Status::failed_precondition(e))).await;
+                        break;
+                    }
+                };
+                let (status, chunk_data) = match load_chunk(&node, &chunk_hash).await {
+                    Ok(data) => (ChunkStatus::Ok, data),
+                    Err(e) if e.code() == Code::DataLoss => (ChunkStatus::Corrupt, Vec::new()),
+                    Err(_) => (ChunkStatus::NotFound, Vec::new()),
+                };
+                let item = GetChunksResponse {
+                    chunk_hash,
+                    status: status.into(),
+                    chunk_data,
+                };
+                if tx.send(Ok(item)).await.is_err() {
+                    // the client went away
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ReadRangeStream = Pin<Box<dyn Stream<Item = Result<ReadRangeResponse, Status>> + Send>>;
+
+    /// Streams part of a file, fetching the metadata and chunks we don't
+    /// hold from the network.
+    async fn read_range(
+        &self,
+        request: Request<ReadRangeRequest>,
+    ) -> Result<Response<Self::ReadRangeStream>, Status> {
+        let req = request.into_inner();
+        let file_hash = parse_key(req.file_hash)?;
+        log::info!(
+            "Reading {} bytes at {} of file {}",
+            req.length,
+            req.offset,
+            hex::encode(file_hash)
+        );
+
+        let metadata = self
+            .node
+            .find_metadata(&file_hash)
+            .await
+            .ok_or_else(|| Status::not_found("File not found"))?;
+        if metadata.directory.is_some() {
+            return Err(Status::invalid_argument("File is a directory"));
         }
+        let pieces = self.node.read_range(metadata, req.offset, req.length);
+        let stream = ReceiverStream::new(pieces).map(|piece| {
+            piece
+                .map(|data| ReadRangeResponse { data })
+                .map_err(Status::unavailable)
+        });
+        Ok(Response::new(Box::pin(stream)))
     }
 
     /// Retrieves file metadata from local storage.
@@ -117,19 +303,13 @@ impl PeerService for PeerServer {
             hex::encode(&file_hash)
         );
 
-        match self.node.get_metadata(&file_hash) {
-            Some(metadata) => {
-                let serialized_metadata = bincode::serialize(&metadata).unwrap();
-                Ok(Response::new(GetFileMetadataResponse {
-                    metadata: serialized_metadata,
-                }))
-            }
+        // served as it was stored, so it matches the hash it was stored under
+        match self.node.get_metadata_bytes(&file_hash) {
+            Some(metadata) => Ok(Response::new(GetFileMetadataResponse { metadata })),
             None => Err(Status::not_found("Metadata not found")),
         }
     }
 
-    /// Stores a replica of a chunk sent from another peer.
-
     async fn list_peers(
         &self,
         _request: Request<crate::storage_proto::ListPeersRequest>,
@@ -139,9 +319,7 @@ impl PeerService for PeerServer {
             .routing_table
             .lock()
             .await
-            .buckets
-            .iter()
-            .flatten()
+            .peers()
             .map(|p| p.address.clone())
             .collect();
         Ok(Response::new(crate::storage_proto::ListPeersResponse {
@@ -173,16 +351,17 @@ impl PeerService for PeerServer {
             hex::encode(&req.file_hash)
         );
 
-        let metadata = req.metadata.unwrap();
+        let metadata: crate::storage::FileInfo = req
+            .metadata
+            .ok_or_else(|| Status::invalid_argument("missing metadata"))?
+            .into();
+        let encoded = metadata.encode();
+        verify_metadata(&req.file_hash, &encoded)?;
 
-        self.node.store_metadata(
-            &req.file_hash,
-            &crate::storage::FileInfo {
-                name: metadata.name,
-                size: metadata.size,
-                chunk_hashes: metadata.chunk_hashes,
-            },
-        );
+        self.node
+            .store_metadata(&req.file_hash, &encoded)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to store metadata: {}", e)))?;
         Ok(Response::new(InitiateUploadResponse { success: true }))
     }
 
@@ -195,19 +374,191 @@ impl PeerService for PeerServer {
             "Received request to upload chunk {}",
             hex::encode(&req.chunk_hash)
         );
+        verify_chunk(&req.chunk_hash, &req.chunk_data)?;
 
-        self.node.store_chunk(&req.chunk_hash, &req.chunk_data);
+        self.node
+            .store_chunk(&req.chunk_hash, &req.chunk_data)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to store chunk: {}", e)))?;
         Ok(Response::new(UploadChunkResponse { success: true }))
     }
 
+    /// Stores every chunk of a client-streamed upload as it arrives.
+    async fn upload_chunks(
+        &self,
+        request: Request<Streaming<UploadChunkRequest>>,
+    ) -> Result<Response<UploadChunksResponse>, Status> {
+        let mut chunks = request.into_inner();
+        let (mut stored, mut reused) = (0, 0);
+        while let Some(chunk) = chunks.message().await? {
+            verify_chunk(&chunk.chunk_hash, &chunk.chunk_data)?;
+            // chunks are content-addressed, so one we have is this exact data
+            if self.node.storage.has_chunk(&chunk.chunk_hash) {
+                reused += 1;
+                continue;
+            }
+            self.node
+                .store_chunk(&chunk.chunk_hash, &chunk.chunk_data)
+                .await
+                .map_err(|e| Status::internal(format!("Failed to store chunk: {}", e)))?;
+            stored += 1;
+        }
+        log::info!(
+            "Received {} chunks in a streamed upload, {} already stored",
+            stored + reused,
+            reused
+        );
+        Ok(Response::new(UploadChunksResponse {
+            chunks_stored: stored,
+            chunks_reused: reused,
+        }))
+    }
+
+    /// Stores a chunk replica pushed by another peer.
+    async fn store_chunk(
+        &self,
+        request: Request<StoreChunkRequest>,
+    ) -> Result<Response<StoreChunkResponse>, Status> {
+        verify_sender(&request, "StoreChunk")?;
+        let req = request.into_inner();
+        log::info!("Storing replica of chunk {}", hex::encode(&req.chunk_hash));
+        verify_chunk(&req.chunk_hash, &req.chunk_data)?;
+
+        self.node
+            .accept_chunk_replica(&req.chunk_hash, &req.chunk_data)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to store chunk: {}", e)))?;
+        Ok(Response::new(StoreChunkResponse { success: true }))
+    }
+
+    /// Stores a file metadata replica pushed by another peer.
+    async fn store_metadata(
+        &self,
+        request: Request<StoreMetadataRequest>,
+    ) -> Result<Response<StoreMetadataResponse>, Status> {
+        verify_sender(&request, "StoreMetadata")?;
+        let req = request.into_inner();
+        log::info!(
+            "Storing replica of metadata {}",
+            hex::encode(&req.file_hash)
+        );
+        let encoded = match req.metadata {
+            _ if !req.encoded.is_empty() => req.encoded,
+            Some(metadata) => crate::storage::FileInfo::from(metadata).encode(),
+            None => return Err(Status::invalid_argument("missing metadata")),
+        };
+        verify_metadata(&req.file_hash, &encoded)?;
+
+        self.node
+            .accept_metadata_replica(&req.file_hash, &encoded)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to store metadata: {}", e)))?;
+        Ok(Response::new(StoreMetadataResponse { success: true }))
+    }
+
+    /// Pushes a locally stored file to the closest peers of its chunks and
+    /// reports how many copies they got, listing the chunks that fell
+    /// short.
+    async fn replicate_file(
+        &self,
+        request: Request<ReplicateFileRequest>,
+    ) -> Result<Response<ReplicateFileResponse>, Status> {
+        let file_hash = parse_key(request.into_inner().file_hash)?;
+        log::info!("Replicating file {}", hex::encode(file_hash));
+
+        let report = self
+            .node
+            .replicate_file(&file_hash)
+            .await
+            .map_err(|e| Status::failed_precondition(format!("Failed to replicate: {}", e)))?;
+        Ok(Response::new(ReplicateFileResponse {
+            target: self.node.config.replication_factor as u32,
+            metadata: Some(replica_acks(
+                file_hash.to_vec(),
+                self.node.config.replication_factor,
+                report.metadata,
+            )),
+            chunks: report.chunks as u64,
+            shards: report.shards as u64,
+            under_replicated: report.under_replicated as u64,
+            under_replicated_chunks: report
+                .reported
+                .into_iter()
+                .map(|(key, target, peers)| replica_acks(key, target, peers))
+                .collect(),
+        }))
+    }
+
+    /// Tells a peer which of the requested chunks we hold.
+    async fn has_chunks(
+        &self,
+        request: Request<HasChunksRequest>,
+    ) -> Result<Response<HasChunksResponse>, Status> {
+        verify_sender(&request, "HasChunks")?;
+        let present = request
+            .into_inner()
+            .chunk_hashes
+            .iter()
+            .map(|hash| self.node.storage.has_chunk(hash))
+            .collect();
+        Ok(Response::new(HasChunksResponse { present }))
+    }
+
+    async fn get_repair_stats(
+        &self,
+        _request: Request<GetRepairStatsRequest>,
+    ) -> Result<Response<GetRepairStatsResponse>, Status> {
+        let metrics = &self.node.repair_metrics;
+        Ok(Response::new(GetRepairStatsResponse {
+            rounds: metrics.rounds.load(Ordering::Relaxed),
+            chunks_checked: metrics.chunks_checked.load(Ordering::Relaxed),
+            under_replicated: metrics.under_replicated.load(Ordering::Relaxed),
+            replicas_pushed: metrics.replicas_pushed.load(Ordering::Relaxed),
+            failed_pushes: metrics.failed_pushes.load(Ordering::Relaxed),
+        }))
+    }
+
     async fn show_chunks(
         &self,
-        request: Request<ShowChunksRequest>,
+        _request: Request<ShowChunksRequest>,
     ) -> Result<Response<ShowChunksResponse>, Status> {
-        let req = request.into_inner();
         log::info!("Received request to show local chunks");
-        let chunks = self.node.storage.get_all_chunks();
-        Ok(Response::new(ShowChunksResponse { chunks: chunks }))
+        let chunks = self.node.storage.chunk_hashes();
+        Ok(Response::new(ShowChunksResponse { chunks }))
+    }
+}
+
+impl From<crate::storage_proto::FileInfo> for crate::storage::FileInfo {
+    fn from(file_info: crate::storage_proto::FileInfo) -> Self {
+        crate::storage::FileInfo {
+            name: file_info.name,
+            size: file_info.size,
+            chunk_hashes: file_info.chunk_hashes,
+            stripes: file_info.stripes.map(|s| crate::storage::StripeLayout {
+                data_shards: s.data_shards,
+                parity_shards: s.parity_shards,
+                shard_size: s.shard_size,
+            }),
+            cdc: file_info.cdc.map(|c| crate::storage::CdcLayout {
+                min_size: c.min_size,
+                avg_size: c.avg_size,
+                max_size: c.max_size,
+            }),
+            manifest_depth: file_info.manifest_depth,
+            directory: file_info.directory.map(|d| crate::storage::DirectoryInfo {
+                entries: d
+                    .entries
+                    .into_iter()
+                    .map(|e| crate::storage::DirectoryEntry {
+                        name: e.name,
+                        mode: e.mode,
+                        size: e.size,
+                        hash: e.hash,
+                    })
+                    .collect(),
+            }),
+            chunk_sizes: file_info.chunk_sizes,
+        }
     }
 }
 
@@ -217,39 +568,638 @@ impl From<crate::storage::FileInfo> for crate::storage_proto::FileInfo {
             name: file_info.name,
             size: file_info.size,
             chunk_hashes: file_info.chunk_hashes,
+            stripes: file_info
+                .stripes
+                .map(|s| crate::storage_proto::StripeLayout {
+                    data_shards: s.data_shards,
+                    parity_shards: s.parity_shards,
+                    shard_size: s.shard_size,
+                }),
+            cdc: file_info.cdc.map(|c| crate::storage_proto::CdcLayout {
+                min_size: c.min_size,
+                avg_size: c.avg_size,
+                max_size: c.max_size,
+            }),
+            manifest_depth: file_info.manifest_depth,
+            directory: file_info
+                .directory
+                .map(|d| crate::storage_proto::DirectoryInfo {
+                    entries: d
+                        .entries
+                        .into_iter()
+                        .map(|e| crate::storage_proto::DirectoryEntry {
+                            name: e.name,
+                            mode: e.mode,
+                            size: e.size,
+                            hash: e.hash,
+                        })
+                        .collect(),
+                }),
+            chunk_sizes: file_info.chunk_sizes,
         }
     }
 }
 
+impl From<ProviderRecord> for crate::storage_proto::ProviderRecord {
+    fn from(record: ProviderRecord) -> Self {
+        Self {
+            node_id: record.node_id.to_vec(),
+            address: record.address,
+            timestamp: record.timestamp,
+            expires_at: record.expires_at,
+        }
+    }
+}
+
+impl TryFrom<crate::storage_proto::ProviderRecord> for ProviderRecord {
+    type Error = Status;
+
+    fn try_from(record: crate::storage_proto::ProviderRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            node_id: parse_key(record.node_id)?,
+            address: record.address,
+            timestamp: record.timestamp,
+            expires_at: record.expires_at,
+        })
+    }
+}
+
+impl TryFrom<PeerMessage> for Peer {
+    type Error = Status;
+
+    /// Rejects peers whose node ID isn't the hash of their public key, or
+    /// whose address other peers couldn't dial.
+    fn try_from(peer: PeerMessage) -> Result<Self, Self::Error> {
+        check_peer_address(&peer.address).map_err(Status::invalid_argument)?;
+        let node_id = parse_key(peer.node_id)?;
+        let public_key = parse_key(peer.public_key)?;
+        if node_id_for(&public_key) != node_id {
+            return Err(Status::unauthenticated(
+                "node ID does not match the peer's public key",
+            ));
+        }
+        Ok(Self {
+            node_id,
+            address: peer.address,
+            public_key,
+        })
+    }
+}
+
 impl From<Peer> for PeerMessage {
     fn from(peer: Peer) -> Self {
         Self {
             node_id: peer.node_id.to_vec(),
             address: peer.address,
+            public_key: peer.public_key.to_vec(),
+        }
+    }
+}
+
+fn replica_acks(key: Vec<u8>, target: usize, peers: Vec<Peer>) -> ReplicaAcks {
+    ReplicaAcks {
+        key,
+        peers: peers.into_iter().map(|p| p.address).collect(),
+        target: target as u32,
+    }
+}
+
+/// Reads a chunk from local storage and checks it against its hash. A chunk
+/// that rotted on disk is never served; dropping it lets the repair loop of
+/// another holder restore it.
+async fn load_chunk(node: &Node, chunk_hash: &[u8]) -> Result<Vec<u8>, Status> {
+    let Some(chunk_data) = node.get_chunk(chunk_hash) else {
+        return Err(Status::not_found("Chunk not found"));
+    };
+    if let Err(status) = verify_chunk(chunk_hash, &chunk_data) {
+        log::error!("Dropping corrupt chunk {}", hex::encode(chunk_hash));
+        if let Err(e) = node.delete_chunk(chunk_hash).await {
+            log::error!("Failed to delete corrupt chunk: {}", e);
+        }
+        return Err(status);
+    }
+    Ok(chunk_data)
+}
+
+/// Checks that chunk data hashes to the hash it is stored under. A mismatch
+/// means the data was corrupted, so it's reported as `DATA_LOSS`.
+fn verify_chunk(chunk_hash: &[u8], data: &[u8]) -> Result<(), Status> {
+    if hash(data) != chunk_hash {
+        return Err(Status::data_loss(format!(
+            "chunk data does not match hash {}",
+            hex::encode(chunk_hash)
+        )));
+    }
+    Ok(())
+}
+
+/// The HTTP gateway's routes, for clients that can't speak gRPC: `GET` a
+/// file by its hash, or `PUT` one under a file name to store it.
+fn gateway(node: Arc<Node>) -> Router {
+    Router::new()
+        .route("/ipfs-style/{key}", get(gateway_get).put(gateway_put))
+        .with_state(node)
+}
+
+type GatewayResult = Result<axum::response::Response, (StatusCode, String)>;
+
+/// Serves a file, or the part of it a `Range` header asks for, reading the
+/// chunks we don't hold from the network.
+async fn gateway_get(
+    State(node): State<Arc<Node>>,
+    axum::extract::Path(key): axum::extract::Path<String>,
+    headers: HeaderMap,
+) -> GatewayResult {
+    let file_hash: [u8; 32] = hex::decode(&key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or((StatusCode::BAD_REQUEST, "not a file hash".to_string()))?;
+    let metadata = node
+        .find_metadata(&file_hash)
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "file not found".to_string()))?;
+    if metadata.directory.is_some() {
+        return Err((StatusCode::BAD_REQUEST, "file is a directory".into()));
+    }
+
+    // the content never changes, so the hash is all a cache has to compare
+    let etag = format!("\"{}\"", key.to_ascii_lowercase());
+    let response = axum::response::Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if matches_if_none_match(&headers, &etag) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok());
+    let (response, offset, end) = match parse_range(range, metadata.size) {
+        ByteRange::Whole => (response.status(StatusCode::OK), 0, metadata.size),
+        ByteRange::Part(offset, end) => (
+            response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, end - 1, metadata.size),
+            ),
+            offset,
+            end,
+        ),
+        ByteRange::Unsatisfiable => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", metadata.size))
+                .body(Body::empty())
+                .unwrap());
+        }
+    };
+    log::info!(
+        "Serving bytes {}..{} of file {} over HTTP",
+        offset,
+        end,
+        key
+    );
+    // an error partway through cuts the response short of its length,
+    // which the client notices
+    let pieces = ReceiverStream::new(node.read_range(metadata, offset, end - offset));
+    Ok(response
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, end - offset)
+        .body(Body::from_stream(pieces))
+        .unwrap())
+}
+
+/// Stores the request body as a file named `name`, cut into fixed-size
+/// chunks like a CLI upload, then announces and replicates it. Responds
+/// with the file hash.
+async fn gateway_put(
+    State(node): State<Arc<Node>>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    body: Body,
+) -> GatewayResult {
+    let internal = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let mut manifest = ManifestBuilder::new(manifest::FANOUT);
+    let mut size = 0;
+    let mut buf = Vec::with_capacity(CHUNK_SIZE);
+    let mut frames = body.into_data_stream();
+    loop {
+        let frame = frames.next().await.transpose().map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("failed to read body: {}", e),
+            )
+        })?;
+        let done = frame.is_none();
+        let mut frame = frame.as_deref().unwrap_or_default();
+        size += frame.len() as u64;
+        // full chunks as they fill up, the last partial one at the end
+        while !frame.is_empty() || (done && !buf.is_empty()) {
+            let take = frame.len().min(CHUNK_SIZE - buf.len());
+            buf.extend_from_slice(&frame[..take]);
+            frame = &frame[take..];
+            if buf.len() < CHUNK_SIZE && !done {
+                break;
+            }
+            let chunk_hash = store_gateway_chunk(&node, &buf).await?;
+            for node_data in manifest.push(chunk_hash, buf.len()) {
+                store_gateway_chunk(&node, &node_data).await?;
+            }
+            buf.clear();
+        }
+        if done {
+            break;
+        }
+    }
+    let top = manifest.finish();
+    for node_data in top.nodes {
+        store_gateway_chunk(&node, &node_data).await?;
+    }
+
+    let metadata = crate::storage::FileInfo {
+        name,
+        size,
+        chunk_hashes: top.chunk_hashes,
+        stripes: None,
+        cdc: None,
+        manifest_depth: top.depth,
+        directory: None,
+        chunk_sizes: Vec::new(),
+    };
+    let encoded = metadata.encode();
+    let file_hash: [u8; 32] = hash(&encoded).try_into().unwrap();
+    node.store_metadata(&file_hash, &encoded)
+        .await
+        .map_err(|e| internal(format!("failed to store metadata: {}", e)))?;
+    log::info!(
+        "Stored {} bytes uploaded over HTTP as file {}",
+        size,
+        hex::encode(file_hash)
+    );
+
+    // announcing and replicating takes a lookup per chunk; the client
+    // needn't wait for it
+    let background = node.clone();
+    tokio::spawn(async move {
+        if let Err(e) = background.provide(&file_hash).await {
+            log::warn!("Failed to announce uploaded file: {}", e);
+        }
+        if let Err(e) = background.replicate_file(&file_hash).await {
+            log::warn!("Failed to replicate uploaded file: {}", e);
+        }
+    });
+
+    let key = hex::encode(file_hash);
+    Ok(axum::response::Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::LOCATION, format!("/ipfs-style/{}", key))
+        .header(header::ETAG, format!("\"{}\"", key))
+        .body(Body::from(format!("{}\n", key)))
+        .unwrap())
+}
+
+/// Stores a chunk of a gateway upload unless we hold it already, and
+/// returns its hash.
+async fn store_gateway_chunk(node: &Node, chunk: &[u8]) -> Result<Vec<u8>, (StatusCode, String)> {
+    let chunk_hash = hash(chunk);
+    // chunks are content-addressed, so one we have is this exact data
+    if !node.storage.has_chunk(&chunk_hash) {
+        node.store_chunk(&chunk_hash, chunk).await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to store chunk: {}", e),
+            )
+        })?;
+    }
+    Ok(chunk_hash)
+}
+
+/// What a `Range` header asks for out of a file of some size.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Whole,
+    /// Bytes `offset..end`.
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range. Anything else, including several
+/// ranges, is answered with the whole file, which the spec allows.
+fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+    let Some((first, last)) = header
+        .and_then(|h| h.strip_prefix("bytes="))
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.trim().split_once('-'))
+    else {
+        return ByteRange::Whole;
+    };
+    let (offset, end) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.saturating_add(1).min(size)),
+        (Ok(first), Err(_)) if last.is_empty() => (first, size),
+        // the last `suffix` bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => (size.saturating_sub(suffix), size),
+        _ => return ByteRange::Whole,
+    };
+    if offset >= end {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(offset, end)
+}
+
+/// Whether the `If-None-Match` fields of a request match `etag`, so the
+/// client's copy is current. Per RFC 9110 each field is `*` or a
+/// comma-separated list of entity tags, compared weakly: `W/"tag"` matches
+/// `"tag"`. A malformed list matches nothing.
+fn matches_if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(header::IF_NONE_MATCH).iter().any(|field| {
+        let Ok(field) = field.to_str() else {
+            return false;
+        };
+        field.trim() == "*" || entity_tags(field).is_some_and(|tags| tags.contains(&etag))
+    })
+}
+
+/// The entity tags of a comma-separated list, quotes included and any weak
+/// `W/` prefix dropped, or `None` if the list is malformed. Commas are
+/// allowed inside tags, so the list can't simply be split on them.
+fn entity_tags(list: &str) -> Option<Vec<&str>> {
+    let mut tags = Vec::new();
+    let mut rest = list;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return Some(tags);
+        }
+        let tag = rest.strip_prefix("W/").unwrap_or(rest);
+        let end = tag.strip_prefix('"')?.find('"')? + 2;
+        tags.push(&tag[..end]);
+        rest = &tag[end..];
+        if !(rest.is_empty() || rest.starts_with([' ', '\t', ','])) {
+            return None;
         }
     }
 }
 
-pub async fn start_server(
-    port: u16,
-    bootstrap_peer: Option<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let addr = format!("0.0.0.0:{}", port).parse()?;
-    let node_addr = format!("http://0.0.0.0:{}", port);
-    let node = Arc::new(Node::new(&node_addr)?);
+/// Checks that file metadata hashes to its file hash, like `verify_chunk`.
+fn verify_metadata(file_hash: &[u8], encoded: &[u8]) -> Result<(), Status> {
+    if hash(encoded) != file_hash {
+        return Err(Status::data_loss(format!(
+            "metadata does not match file hash {}",
+            hex::encode(file_hash)
+        )));
+    }
+    Ok(())
+}
 
-    let peer_server = PeerServer { node: node.clone() };
+/// Checks that a node ID, public key or DHT key from a request is 256 bits
+/// long.
+fn parse_key(key: Vec<u8>) -> Result<[u8; 32], Status> {
+    key.try_into()
+        .map_err(|_| Status::invalid_argument("keys and node IDs must be 32 bytes"))
+}
+
+pub async fn start_server(args: ServerArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = args
+        .listen
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], args.port)));
+    if let Some(dir) = &args.data_dir {
+        log::info!(
+            "Using {:?} storage in data directory {}",
+            args.storage_backend,
+            dir.display()
+        );
+    }
+    let storage = open_backend(args.storage_backend, args.data_dir.as_deref())?;
+    let config = NodeConfig {
+        alpha: args.lookup_alpha,
+        rpc_timeout: Duration::from_millis(args.rpc_timeout_ms),
+        record_ttl: Duration::from_secs(args.record_ttl_secs),
+        republish_interval: Duration::from_secs(args.republish_interval_secs),
+        replicate_interval: Duration::from_secs(args.replicate_interval_secs),
+        expiry_interval: Duration::from_secs(args.expiry_interval_secs),
+        maintenance_interval: Duration::from_secs(args.maintenance_interval_secs),
+        bucket_refresh_interval: Duration::from_secs(args.bucket_refresh_interval_secs),
+        replication_factor: args.replication_factor,
+        repair_interval: Duration::from_secs(args.repair_interval_secs),
+    };
+    let identity = Identity::load_or_generate(args.data_dir.as_deref())?;
+    let node = Arc::new(Node::new(
+        addr,
+        args.advertise_addr,
+        identity,
+        storage,
+        config,
+    )?);
+    log::info!("Node ID {}", hex::encode(node.id));
+    match node.address() {
+        Some(address) => log::info!("Advertising {}", address),
+        None => log::info!("No address to advertise yet, waiting to learn it from peers"),
+    }
+
+    let peer_server = PeerServer::new(node.clone());
 
     log::info!("Server listening on {}", addr);
 
     // Start the node's background tasks (bootstrapping)
-    node.start(bootstrap_peer).await?;
+    let handle = node
+        .start(args.bootstrap_peer)
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+
+    let gateway_task = match args.http_listen {
+        Some(http_addr) => {
+            let listener = tokio::net::TcpListener::bind(http_addr).await?;
+            log::info!("HTTP gateway listening on {}", http_addr);
+            let router = gateway(node.clone());
+            Some(tokio::spawn(async move {
+                let served = axum::serve(listener, router)
+                    .with_graceful_shutdown(async {
+                        let _ = tokio::signal::ctrl_c().await;
+                    })
+                    .await;
+                if let Err(e) = served {
+                    log::error!("HTTP gateway failed: {}", e);
+                }
+            }))
+        }
+        None => None,
+    };
 
     // Start the gRPC server
-    Server::builder()
-        .add_service(PeerServiceServer::new(peer_server))
-        .serve(addr)
-        .await?;
+    let served = Server::builder()
+        .add_service(PeerServiceServer::with_interceptor(
+            peer_server,
+            Authenticator::new(),
+        ))
+        .serve_with_shutdown(addr, async {
+            let _ = tokio::signal::ctrl_c().await;
+            log::info!("Shutting down");
+        })
+        .await;
 
+    handle.shutdown().await;
+    if let Some(task) = gateway_task {
+        let _ = task.await;
+    }
+    served?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        let range = |header| parse_range(Some(header), 100);
+        assert_eq!(range("bytes=0-9"), ByteRange::Part(0, 10));
+        assert_eq!(range("bytes=90-"), ByteRange::Part(90, 100));
+        assert_eq!(range("bytes=90-500"), ByteRange::Part(90, 100));
+        assert_eq!(range("bytes=-10"), ByteRange::Part(90, 100));
+        assert_eq!(range("bytes=100-"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-1,5-6"), ByteRange::Whole);
+        assert_eq!(range("bytes=9-0"), ByteRange::Whole);
+        assert_eq!(range("items=0-1"), ByteRange::Whole);
+        assert_eq!(parse_range(None, 100), ByteRange::Whole);
+    }
+
+    #[test]
+    fn parses_if_none_match_lists() {
+        assert_eq!(entity_tags(r#""a""#), Some(vec![r#""a""#]));
+        assert_eq!(
+            entity_tags(r#"W/"a", "b,c" ,W/"d""#),
+            Some(vec![r#""a""#, r#""b,c""#, r#""d""#])
+        );
+        assert_eq!(entity_tags(""), Some(vec![]));
+        assert_eq!(entity_tags(r#""a"b"#), None);
+        assert_eq!(entity_tags(r#""a"#), None);
+        assert_eq!(entity_tags("a"), None);
+
+        let mut headers = HeaderMap::new();
+        assert!(!matches_if_none_match(&headers, r#""a""#));
+        headers.append(header::IF_NONE_MATCH, r#""x", W/"y""#.parse().unwrap());
+        assert!(!matches_if_none_match(&headers, r#""a""#));
+        assert!(matches_if_none_match(&headers, r#""y""#));
+        headers.append(header::IF_NONE_MATCH, "*".parse().unwrap());
+        assert!(matches_if_none_match(&headers, r#""a""#));
+    }
+
+    #[test]
+    fn metadata_encodes_the_same_after_a_trip_through_the_wire_format() {
+        let metadata = crate::storage::FileInfo {
+            name: "dir".into(),
+            size: 42,
+            chunk_hashes: vec![hash(b"a"), hash(b"b")],
+            stripes: Some(crate::storage::StripeLayout {
+                data_shards: 4,
+                parity_shards: 2,
+                shard_size: 1 << 20,
+            }),
+            cdc: Some(crate::storage::CdcLayout {
+                min_size: 1,
+                avg_size: 2,
+                max_size: 3,
+            }),
+            manifest_depth: 1,
+            directory: Some(crate::storage::DirectoryInfo {
+                entries: vec![crate::storage::DirectoryEntry {
+                    name: "file".into(),
+                    mode: 0o644,
+                    size: 42,
+                    hash: hash(b"file"),
+                }],
+            }),
+            chunk_sizes: vec![20, 22],
+        };
+        let wire = crate::storage_proto::FileInfo::from(metadata.clone());
+        let back = crate::storage::FileInfo::from(wire);
+        assert_eq!(back.encode(), metadata.encode());
+    }
+
+    #[tokio::test]
+    async fn uploads_store_new_chunks_reuse_known_ones_and_reject_corrupt_ones() {
+        use crate::storage_proto::peer_service_client::PeerServiceClient;
+
+        let node = crate::testing::spawn_node(crate::testing::test_config()).await;
+        let mut client = PeerServiceClient::connect(node.address().unwrap())
+            .await
+            .unwrap();
+        let chunk = |data: &[u8]| UploadChunkRequest {
+            chunk_hash: hash(data),
+            chunk_data: data.to_vec(),
+        };
+        node.store_chunk(&hash(b"known"), b"known").await.unwrap();
+
+        let upload = vec![chunk(b"one"), chunk(b"two"), chunk(b"one"), chunk(b"known")];
+        let response = client
+            .upload_chunks(tokio_stream::iter(upload))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((response.chunks_stored, response.chunks_reused), (2, 2));
+        assert_eq!(node.get_chunk(&hash(b"two")), Some(b"two".to_vec()));
+
+        let corrupt = UploadChunkRequest {
+            chunk_hash: hash(b"three"),
+            chunk_data: b"tree".to_vec(),
+        };
+        let status = client
+            .upload_chunks(tokio_stream::iter(vec![corrupt]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DataLoss);
+        assert!(!node.storage.has_chunk(&hash(b"three")));
+    }
+
+    #[tokio::test]
+    async fn gateway_serves_whole_files_ranges_and_cache_hits() {
+        let node = crate::testing::spawn_node(crate::testing::test_config()).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ipfs-style", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, gateway(Arc::new(node))).await });
+        let client = reqwest::Client::new();
+
+        let data: Vec<u8> = (0..100).collect();
+        let put = client
+            .put(format!("{}/numbers.bin", url))
+            .body(data.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(put.status(), StatusCode::CREATED);
+        let key = put.text().await.unwrap().trim().to_string();
+        let file_url = format!("{}/{}", url, key);
+        let get = |headers: &[(header::HeaderName, &str)]| {
+            let mut request = client.get(&file_url);
+            for (name, value) in headers {
+                request = request.header(name.clone(), *value);
+            }
+            request.send()
+        };
+
+        let whole = get(&[]).await.unwrap();
+        assert_eq!(whole.status(), StatusCode::OK);
+        assert_eq!(whole.bytes().await.unwrap(), data);
+
+        let part = get(&[(header::RANGE, "bytes=10-19")]).await.unwrap();
+        assert_eq!(part.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(part.headers()[header::CONTENT_RANGE], "bytes 10-19/100");
+        assert_eq!(part.bytes().await.unwrap(), data[10..20]);
+
+        let weak = format!("\"other\", W/\"{}\"", key);
+        for tags in [weak.as_str(), "*"] {
+            let cached = get(&[(header::IF_NONE_MATCH, tags), (header::RANGE, "bytes=0-1")])
+                .await
+                .unwrap();
+            assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        }
+        let stale = get(&[(header::IF_NONE_MATCH, "\"other\"")]).await.unwrap();
+        assert_eq!(stale.status(), StatusCode::OK);
+
+        let past_end = get(&[(header::RANGE, "bytes=100-")]).await.unwrap();
+        assert_eq!(past_end.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(past_end.headers()[header::CONTENT_RANGE], "bytes */100");
+    }
+}
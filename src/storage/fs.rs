@@ -0,0 +1,416 @@
+use super::{
+    decode_metadata, merge_provider, FileInfo, MetadataIndex, ProviderRecord, StorageBackend,
+};
+use crate::utils::hash;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+const CHUNKS_DIR: &str = "chunks";
+const METADATA_DIR: &str = "metadata";
+const PROVIDERS_DIR: &str = "providers";
+const TMP_EXTENSION: &str = "tmp";
+
+/// Stores every entry as its own file under the data directory.
+///
+/// Chunks are kept as content-addressed files under `chunks/`, while file
+/// metadata and DHT provider records live under `metadata/` and `providers/`.
+/// Metadata and providers are small, so they are also cached in memory; chunk
+/// contents are only read from disk on demand.
+pub struct FsBackend {
+    data_dir: PathBuf,
+    chunk_index: RwLock<HashSet<Vec<u8>>>,
+    metadata: RwLock<MetadataIndex>,
+    providers: RwLock<HashMap<Vec<u8>, Vec<ProviderRecord>>>,
+    // held across provider updates, fsync included, so none is lost;
+    // lookups only ever wait for `providers`
+    provider_writes: Mutex<()>,
+}
+
+impl FsBackend {
+    /// Opens the store rooted at `data_dir`, creating it if needed, and
+    /// rebuilds the in-memory index from whatever survived on disk.
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        for dir in [CHUNKS_DIR, METADATA_DIR, PROVIDERS_DIR] {
+            fs::create_dir_all(data_dir.join(dir))?;
+        }
+
+        let backend = Self {
+            data_dir: data_dir.to_path_buf(),
+            chunk_index: RwLock::default(),
+            metadata: RwLock::default(),
+            providers: RwLock::default(),
+            provider_writes: Mutex::default(),
+        };
+        backend.recover()?;
+        Ok(backend)
+    }
+
+    fn entry_path(&self, kind: &str, key: &[u8]) -> PathBuf {
+        self.data_dir.join(kind).join(hex::encode(key))
+    }
+
+    /// Persists a key's provider list, removing the file once it is empty.
+    fn write_providers(&self, key: &[u8], records: &[ProviderRecord]) -> io::Result<()> {
+        if records.is_empty() {
+            return self.delete_entry(PROVIDERS_DIR, key);
+        }
+        let encoded = bincode::serialize(records)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomic(&self.entry_path(PROVIDERS_DIR, key), &encoded)
+    }
+
+    fn delete_entry(&self, kind: &str, key: &[u8]) -> io::Result<()> {
+        match fs::remove_file(self.entry_path(kind, key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rebuilds the in-memory index from the data directory.
+    ///
+    /// Leftover temporary files from interrupted writes are removed. Chunk
+    /// contents aren't read, so startup doesn't grow with the size of the
+    /// store; a chunk that no longer matches its hash is caught and dropped
+    /// when it is served.
+    fn recover(&self) -> io::Result<()> {
+        let mut chunk_index = self.chunk_index.write().unwrap();
+        for (key, _) in read_entries(&self.data_dir.join(CHUNKS_DIR))? {
+            chunk_index.insert(key);
+        }
+
+        let mut metadata = self.metadata.write().unwrap();
+        for (key, path) in read_entries(&self.data_dir.join(METADATA_DIR))? {
+            let encoded = fs::read(&path)?;
+            match FileInfo::decode(&encoded) {
+                Ok(info) => {
+                    metadata.insert(key, (info, encoded));
+                }
+                Err(e) => log::warn!("Skipping unreadable metadata {}: {}", path.display(), e),
+            }
+        }
+
+        let mut providers = self.providers.write().unwrap();
+        for (key, path) in read_entries(&self.data_dir.join(PROVIDERS_DIR))? {
+            match bincode::deserialize::<Vec<ProviderRecord>>(&fs::read(&path)?) {
+                Ok(records) => {
+                    providers.insert(key, records);
+                }
+                Err(e) => log::warn!("Skipping unreadable providers {}: {}", path.display(), e),
+            }
+        }
+
+        log::info!(
+            "Recovered {} chunks, {} files and {} provider keys from {}",
+            chunk_index.len(),
+            metadata.len(),
+            providers.len(),
+            self.data_dir.display()
+        );
+        Ok(())
+    }
+}
+
+impl StorageBackend for FsBackend {
+    fn store_chunk(&self, chunk_hash: &[u8], data: &[u8]) -> io::Result<()> {
+        let path = self.entry_path(CHUNKS_DIR, chunk_hash);
+        // a copy in place is as good as writing the chunk again. Where the
+        // index and the disk agree on it, that is taken on trust, since reads
+        // verify chunks anyway; only an unindexed file, left by a store
+        // racing this one, is read back and checked
+        let on_disk = fs::metadata(&path).map(|m| m.len()).ok();
+        let in_place = match on_disk {
+            Some(len) if self.has_chunk(chunk_hash) => len == data.len() as u64,
+            Some(_) => fs::read(&path).is_ok_and(|existing| hash(&existing) == chunk_hash),
+            None => false,
+        };
+        if !in_place {
+            write_atomic(&path, data)?;
+        }
+        self.chunk_index
+            .write()
+            .unwrap()
+            .insert(chunk_hash.to_vec());
+        Ok(())
+    }
+
+    fn get_chunk(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        if !self.has_chunk(hash) {
+            return None;
+        }
+        match fs::read(self.entry_path(CHUNKS_DIR, hash)) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                log::error!("Failed to read chunk {}: {}", hex::encode(hash), e);
+                None
+            }
+        }
+    }
+
+    fn has_chunk(&self, hash: &[u8]) -> bool {
+        self.chunk_index.read().unwrap().contains(hash)
+    }
+
+    fn delete_chunk(&self, hash: &[u8]) -> io::Result<bool> {
+        if !self.chunk_index.write().unwrap().remove(hash) {
+            return Ok(false);
+        }
+        self.delete_entry(CHUNKS_DIR, hash)?;
+        Ok(true)
+    }
+
+    fn chunk_hashes(&self) -> Vec<Vec<u8>> {
+        self.chunk_index.read().unwrap().iter().cloned().collect()
+    }
+
+    fn store_metadata(&self, hash: &[u8], encoded: &[u8]) -> io::Result<()> {
+        let metadata = decode_metadata(encoded)?;
+        write_atomic(&self.entry_path(METADATA_DIR, hash), encoded)?;
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(hash.to_vec(), (metadata, encoded.to_vec()));
+        Ok(())
+    }
+
+    fn get_metadata(&self, hash: &[u8]) -> Option<FileInfo> {
+        Some(self.metadata.read().unwrap().get(hash)?.0.clone())
+    }
+
+    fn get_metadata_bytes(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        Some(self.metadata.read().unwrap().get(hash)?.1.clone())
+    }
+
+    fn metadata_entries(&self) -> Vec<(Vec<u8>, FileInfo)> {
+        self.metadata
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, (v, _))| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn add_provider(&self, key: &[u8], record: &ProviderRecord) -> io::Result<()> {
+        let _writing = self.provider_writes.lock().unwrap();
+        let mut records = self.get_providers(key);
+        merge_provider(&mut records, record);
+        self.write_providers(key, &records)?;
+        self.providers
+            .write()
+            .unwrap()
+            .insert(key.to_vec(), records);
+        Ok(())
+    }
+
+    fn get_providers(&self, key: &[u8]) -> Vec<ProviderRecord> {
+        self.providers
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn remove_provider(&self, key: &[u8], node_id: &[u8; 32]) -> io::Result<bool> {
+        let _writing = self.provider_writes.lock().unwrap();
+        let Some(mut records) = self.providers.read().unwrap().get(key).cloned() else {
+            return Ok(false);
+        };
+        let before = records.len();
+        records.retain(|p| &p.node_id != node_id);
+        if records.len() == before {
+            return Ok(false);
+        }
+        self.write_providers(key, &records)?;
+        let mut providers = self.providers.write().unwrap();
+        if records.is_empty() {
+            providers.remove(key);
+        } else {
+            providers.insert(key.to_vec(), records);
+        }
+        Ok(true)
+    }
+
+    fn provider_entries(&self) -> Vec<(Vec<u8>, Vec<ProviderRecord>)> {
+        self.providers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+/// Lists the `(key, path)` pairs stored in one of the data directories,
+/// deleting any temporary files left behind by a crash.
+fn read_entries(dir: &Path) -> io::Result<Vec<(Vec<u8>, PathBuf)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == TMP_EXTENSION) {
+            fs::remove_file(&path)?;
+            continue;
+        }
+        let key = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| hex::decode(name).ok());
+        match key {
+            Some(key) => entries.push((key, path)),
+            None => log::warn!("Ignoring unexpected file {}", path.display()),
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes `data` to `path` so that readers either see the old contents or the
+/// complete new ones: the data goes to a temporary file which is fsynced,
+/// renamed over the target, and the parent directory is fsynced as well.
+///
+/// Every write gets a temporary file of its own, so concurrent writers of the
+/// same path never share one; the last rename wins.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(
+        ".{}-{}.{}",
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed),
+        TMP_EXTENSION
+    ));
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    if let Some(parent) = path.parent() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ufs-fs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file_info(name: &str) -> FileInfo {
+        FileInfo {
+            name: name.to_string(),
+            size: 3,
+            chunk_hashes: vec![hash(b"abc")],
+            stripes: None,
+            cdc: None,
+            manifest_depth: 0,
+            directory: None,
+            chunk_sizes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn reopening_recovers_entries_and_deletes() {
+        let dir = test_dir("reopen");
+        let record = ProviderRecord {
+            node_id: [7; 32],
+            address: "http://127.0.0.1:7001".to_string(),
+            timestamp: 1,
+            expires_at: 100,
+        };
+        let backend = FsBackend::open(&dir).unwrap();
+        backend.store_chunk(&hash(b"abc"), b"abc").unwrap();
+        backend.store_chunk(&hash(b"def"), b"def").unwrap();
+        backend
+            .store_metadata(b"file", &file_info("a.txt").encode())
+            .unwrap();
+        backend.add_provider(b"key", &record).unwrap();
+        backend.add_provider(b"gone", &record).unwrap();
+        assert!(backend.delete_chunk(&hash(b"def")).unwrap());
+        assert!(backend.remove_provider(b"gone", &record.node_id).unwrap());
+        drop(backend);
+
+        let backend = FsBackend::open(&dir).unwrap();
+        assert_eq!(backend.get_chunk(&hash(b"abc")), Some(b"abc".to_vec()));
+        assert!(!backend.has_chunk(&hash(b"def")));
+        assert_eq!(backend.get_metadata(b"file").unwrap().name, "a.txt");
+        assert_eq!(backend.get_providers(b"key"), vec![record]);
+        assert!(backend.get_providers(b"gone").is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_of_earlier_versions_is_kept_as_stored() {
+        let dir = test_dir("legacy");
+        drop(FsBackend::open(&dir).unwrap());
+        // before erasure coding, FileInfo only had these fields
+        let legacy = bincode::serialize(&("old.txt", 3u64, vec![hash(b"abc")])).unwrap();
+        fs::write(dir.join(METADATA_DIR).join(hex::encode(b"old")), &legacy).unwrap();
+
+        let backend = FsBackend::open(&dir).unwrap();
+        let info = backend.get_metadata(b"old").unwrap();
+        assert_eq!((info.name.as_str(), info.size), ("old.txt", 3));
+        assert!(info.stripes.is_none() && info.directory.is_none());
+        assert_eq!(backend.get_metadata_bytes(b"old"), Some(legacy));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn opening_removes_leftover_temporary_files() {
+        let dir = test_dir("tmp");
+        drop(FsBackend::open(&dir).unwrap());
+        let chunk_path = dir.join(CHUNKS_DIR).join(hex::encode(hash(b"abc")));
+        let leftover = chunk_path.with_extension("123-0.tmp");
+        fs::write(&leftover, b"ab").unwrap();
+
+        let backend = FsBackend::open(&dir).unwrap();
+        assert!(!leftover.exists());
+        assert!(backend.chunk_hashes().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_stores_of_a_chunk_all_succeed() {
+        let dir = test_dir("concurrent");
+        let backend = FsBackend::open(&dir).unwrap();
+        let data = vec![5u8; 64 * 1024];
+        let chunk_hash = hash(&data);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| backend.store_chunk(&chunk_hash, &data).unwrap());
+            }
+        });
+        assert_eq!(backend.get_chunk(&chunk_hash), Some(data));
+        // nothing is left behind but the chunk itself
+        assert_eq!(fs::read_dir(dir.join(CHUNKS_DIR)).unwrap().count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn storing_a_chunk_again_replaces_only_a_copy_that_disagrees() {
+        let dir = test_dir("restore");
+        let backend = FsBackend::open(&dir).unwrap();
+        let path = backend.entry_path(CHUNKS_DIR, &hash(b"abc"));
+        backend.store_chunk(&hash(b"abc"), b"abc").unwrap();
+
+        // a truncated or missing file is written again
+        fs::write(&path, b"ab").unwrap();
+        backend.store_chunk(&hash(b"abc"), b"abc").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abc");
+        fs::remove_file(&path).unwrap();
+        backend.store_chunk(&hash(b"abc"), b"abc").unwrap();
+        assert_eq!(backend.get_chunk(&hash(b"abc")), Some(b"abc".to_vec()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use super::{
+    decode_metadata, merge_provider, FileInfo, MetadataIndex, ProviderRecord, StorageBackend,
+};
+use std::collections::HashMap;
+use std::io;
+use std::sync::RwLock;
+
+/// Keeps everything in process memory; all data is lost when the node stops.
+#[derive(Default)]
+pub struct MemoryBackend {
+    chunks: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
+    metadata: RwLock<MetadataIndex>,
+    providers: RwLock<HashMap<Vec<u8>, Vec<ProviderRecord>>>,
+}
+
+impl MemoryBackend {
+    /// Creates a new in-memory storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn store_chunk(&self, hash: &[u8], data: &[u8]) -> io::Result<()> {
+        self.chunks
+            .write()
+            .unwrap()
+            .insert(hash.to_vec(), data.to_vec());
+        Ok(())
+    }
+
+    fn get_chunk(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        self.chunks.read().unwrap().get(hash).cloned()
+    }
+
+    fn has_chunk(&self, hash: &[u8]) -> bool {
+        self.chunks.read().unwrap().contains_key(hash)
+    }
+
+    fn delete_chunk(&self, hash: &[u8]) -> io::Result<bool> {
+        Ok(self.chunks.write().unwrap().remove(hash).is_some())
+    }
+
+    fn chunk_hashes(&self) -> Vec<Vec<u8>> {
+        self.chunks.read().unwrap().keys().cloned().collect()
+    }
+
+    fn store_metadata(&self, hash: &[u8], encoded: &[u8]) -> io::Result<()> {
+        let metadata = decode_metadata(encoded)?;
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(hash.to_vec(), (metadata, encoded.to_vec()));
+        Ok(())
+    }
+
+    fn get_metadata(&self, hash: &[u8]) -> Option<FileInfo> {
+        Some(self.metadata.read().unwrap().get(hash)?.0.clone())
+    }
+
+    fn get_metadata_bytes(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        Some(self.metadata.read().unwrap().get(hash)?.1.clone())
+    }
+
+    fn metadata_entries(&self) -> Vec<(Vec<u8>, FileInfo)> {
+        self.metadata
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, (v, _))| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn add_provider(&self, key: &[u8], record: &ProviderRecord) -> io::Result<()> {
+        let mut providers = self.providers.write().unwrap();
+        merge_provider(providers.entry(key.to_vec()).or_default(), record);
+        Ok(())
+    }
+
+    fn get_providers(&self, key: &[u8]) -> Vec<ProviderRecord> {
+        self.providers
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn remove_provider(&self, key: &[u8], node_id: &[u8; 32]) -> io::Result<bool> {
+        let mut providers = self.providers.write().unwrap();
+        let Some(records) = providers.get_mut(key) else {
+            return Ok(false);
+        };
+        let before = records.len();
+        records.retain(|p| &p.node_id != node_id);
+        let removed = records.len() != before;
+        if records.is_empty() {
+            providers.remove(key);
+        }
+        Ok(removed)
+    }
+
+    fn provider_entries(&self) -> Vec<(Vec<u8>, Vec<ProviderRecord>)> {
+        self.providers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
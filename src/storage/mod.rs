@@ -0,0 +1,249 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+mod fs;
+mod memory;
+mod segment;
+
+pub use fs::FsBackend;
+pub use memory::MemoryBackend;
+pub use segment::SegmentLogBackend;
+
+/// The durable backends a node can persist its data directory with.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum BackendKind {
+    /// One file per chunk, metadata entry and value.
+    #[default]
+    Fs,
+    /// Append-only segment log files.
+    Segment,
+}
+
+/// Opens the backend of the given kind under `data_dir`, or an in-memory
+/// backend when no data directory is configured.
+pub fn open_backend(
+    kind: BackendKind,
+    data_dir: Option<&Path>,
+) -> io::Result<Arc<dyn StorageBackend>> {
+    let Some(dir) = data_dir else {
+        return Ok(Arc::new(MemoryBackend::new()));
+    };
+    Ok(match kind {
+        BackendKind::Fs => Arc::new(FsBackend::open(dir)?),
+        BackendKind::Segment => Arc::new(SegmentLogBackend::open(dir)?),
+    })
+}
+
+/// Represents the metadata for a single file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    pub chunk_hashes: Vec<Vec<u8>>,
+    /// Set for erasure-coded files, whose `chunk_hashes` are the shards of
+    /// each stripe in turn.
+    pub stripes: Option<StripeLayout>,
+    /// Set for files cut into content-defined chunks.
+    pub cdc: Option<CdcLayout>,
+    /// How many levels of manifest nodes are between `chunk_hashes` and the
+    /// file's chunks; 0 if there are none.
+    pub manifest_depth: u32,
+    /// Set for directories, which have no chunks of their own.
+    pub directory: Option<DirectoryInfo>,
+    /// The bytes under each of `chunk_hashes`, for content-defined chunked
+    /// files; empty otherwise, and for files uploaded before it was recorded.
+    pub chunk_sizes: Vec<u64>,
+}
+
+impl FileInfo {
+    /// The encoding a file hash is computed over.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("file metadata always encodes")
+    }
+
+    /// Decodes metadata encoded by this or any earlier version. Fields are
+    /// only ever appended to `FileInfo`, so metadata from before a field
+    /// existed simply ends where that field would start, and it keeps its
+    /// default.
+    pub fn decode(encoded: &[u8]) -> bincode::Result<Self> {
+        let mut rest = encoded;
+        let mut info = FileInfo {
+            name: bincode::deserialize_from(&mut rest)?,
+            size: bincode::deserialize_from(&mut rest)?,
+            chunk_hashes: bincode::deserialize_from(&mut rest)?,
+            stripes: None,
+            cdc: None,
+            manifest_depth: 0,
+            directory: None,
+            chunk_sizes: Vec::new(),
+        };
+        if !rest.is_empty() {
+            info.stripes = bincode::deserialize_from(&mut rest)?;
+        }
+        if !rest.is_empty() {
+            info.cdc = bincode::deserialize_from(&mut rest)?;
+        }
+        if !rest.is_empty() {
+            info.manifest_depth = bincode::deserialize_from(&mut rest)?;
+        }
+        if !rest.is_empty() {
+            info.directory = bincode::deserialize_from(&mut rest)?;
+        }
+        if !rest.is_empty() {
+            info.chunk_sizes = bincode::deserialize_from(&mut rest)?;
+        }
+        if !rest.is_empty() {
+            return Err(Box::new(bincode::ErrorKind::Custom(
+                "trailing bytes after file metadata".into(),
+            )));
+        }
+        Ok(info)
+    }
+}
+
+/// How an erasure-coded file is split into Reed-Solomon stripes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct StripeLayout {
+    pub data_shards: u32,
+    pub parity_shards: u32,
+    pub shard_size: u64,
+}
+
+/// The size bounds a file was cut into content-defined chunks with.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct CdcLayout {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+/// The entries of an uploaded directory, each one a file or directory
+/// stored under its own hash.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirectoryInfo {
+    pub entries: Vec<DirectoryEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub mode: u32,
+    pub size: u64,
+    pub hash: Vec<u8>,
+}
+
+// how many providers are remembered per key; the oldest records are dropped
+pub const MAX_PROVIDERS_PER_KEY: usize = 20;
+
+/// A DHT record saying that the node `node_id`, reachable at `address`, can
+/// serve the data stored under a key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProviderRecord {
+    pub node_id: [u8; 32],
+    pub address: String,
+    // unix time in seconds when the record was created
+    pub timestamp: u64,
+    // unix time in seconds after which the record must be dropped
+    pub expires_at: u64,
+}
+
+impl ProviderRecord {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// Stored file metadata: its encoding and what it decodes to, keyed by file
+/// hash.
+pub(crate) type MetadataIndex = HashMap<Vec<u8>, (FileInfo, Vec<u8>)>;
+
+/// Decodes metadata about to be stored, reporting bad metadata as invalid
+/// data.
+pub(crate) fn decode_metadata(encoded: &[u8]) -> io::Result<FileInfo> {
+    FileInfo::decode(encoded).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Adds `record` to a key's providers, replacing any older record from the
+/// same node, and keeps the list ordered newest first and bounded.
+pub(crate) fn merge_provider(providers: &mut Vec<ProviderRecord>, record: &ProviderRecord) {
+    if let Some(existing) = providers.iter().find(|p| p.node_id == record.node_id) {
+        if existing.timestamp > record.timestamp {
+            return;
+        }
+    }
+    providers.retain(|p| p.node_id != record.node_id);
+    providers.push(record.clone());
+    providers.sort_by_key(|p| std::cmp::Reverse(p.timestamp));
+    providers.truncate(MAX_PROVIDERS_PER_KEY);
+}
+
+/// Where a node keeps its chunks, file metadata and DHT provider records.
+///
+/// Implementations must be safe to share between the gRPC handlers and the
+/// node's background tasks. Writes return once the data is as durable as the
+/// backend can make it; reads report missing or unreadable entries as `None`.
+pub trait StorageBackend: Send + Sync {
+    // stores a raw data chunk, keyed by its SHA256 hash.
+    fn store_chunk(&self, hash: &[u8], data: &[u8]) -> io::Result<()>;
+
+    fn get_chunk(&self, hash: &[u8]) -> Option<Vec<u8>>;
+
+    fn has_chunk(&self, hash: &[u8]) -> bool {
+        self.get_chunk(hash).is_some()
+    }
+
+    /// Removes a chunk, returning whether it was present.
+    fn delete_chunk(&self, hash: &[u8]) -> io::Result<bool>;
+
+    /// Hashes of every chunk currently held.
+    fn chunk_hashes(&self) -> Vec<Vec<u8>>;
+
+    /// Stores file metadata exactly as encoded, so it keeps matching its
+    /// hash whatever version of `FileInfo` it was encoded by.
+    fn store_metadata(&self, hash: &[u8], encoded: &[u8]) -> io::Result<()>;
+
+    fn get_metadata(&self, hash: &[u8]) -> Option<FileInfo>;
+
+    /// The metadata stored under `hash` in the encoding it was stored in.
+    fn get_metadata_bytes(&self, hash: &[u8]) -> Option<Vec<u8>>;
+
+    /// Every stored `(file hash, metadata)` pair.
+    fn metadata_entries(&self) -> Vec<(Vec<u8>, FileInfo)>;
+
+    fn get_all_metadata(&self) -> Vec<FileInfo> {
+        self.metadata_entries()
+            .into_iter()
+            .map(|(_, info)| info)
+            .collect()
+    }
+
+    /// Adds or refreshes a provider record for `key`.
+    fn add_provider(&self, key: &[u8], record: &ProviderRecord) -> io::Result<()>;
+
+    /// Every provider known for `key`, newest first.
+    fn get_providers(&self, key: &[u8]) -> Vec<ProviderRecord>;
+
+    /// Forgets the record of one provider of `key`, returning whether it was
+    /// present.
+    fn remove_provider(&self, key: &[u8], node_id: &[u8; 32]) -> io::Result<bool>;
+
+    /// Every stored `(key, providers)` pair.
+    fn provider_entries(&self) -> Vec<(Vec<u8>, Vec<ProviderRecord>)>;
+
+    /// Drops every provider record that has expired by `now`, returning how
+    /// many were removed.
+    fn expire_providers(&self, now: u64) -> io::Result<usize> {
+        let mut removed = 0;
+        for (key, records) in self.provider_entries() {
+            for record in records.iter().filter(|r| r.is_expired(now)) {
+                if self.remove_provider(&key, &record.node_id)? {
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
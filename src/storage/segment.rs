@@ -0,0 +1,466 @@
+use super::{
+    decode_metadata, merge_provider, FileInfo, MetadataIndex, ProviderRecord, StorageBackend,
+};
+use crate::utils::hash;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+const SEGMENTS_DIR: &str = "segments";
+const SEGMENT_EXTENSION: &str = "log";
+// start a new segment file once the current one grows past this
+const MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+// checksum (8) | kind (1) | op (1) | key length (4) | value length (4)
+const CHECKSUM_LEN: usize = 8;
+const HEADER_LEN: usize = CHECKSUM_LEN + 1 + 1 + 4 + 4;
+
+const KIND_CHUNK: u8 = 0;
+const KIND_METADATA: u8 = 1;
+const KIND_PROVIDERS: u8 = 2;
+
+const OP_PUT: u8 = 0;
+const OP_DELETE: u8 = 1;
+
+/// Where a chunk's bytes live inside the log.
+#[derive(Clone, Copy)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u32,
+}
+
+struct Writer {
+    segment: u64,
+    file: File,
+    len: u64,
+}
+
+/// Stores every write as a record appended to a log split into segment files.
+///
+/// Each record carries a checksum, so a write torn by a crash is detected and
+/// cut off on the next start. Deletions append tombstones; space held by
+/// overwritten or deleted entries is not reclaimed.
+pub struct SegmentLogBackend {
+    dir: PathBuf,
+    writer: Mutex<Writer>,
+    chunks: RwLock<HashMap<Vec<u8>, Location>>,
+    metadata: RwLock<MetadataIndex>,
+    providers: RwLock<HashMap<Vec<u8>, Vec<ProviderRecord>>>,
+}
+
+impl SegmentLogBackend {
+    /// Opens the log under `data_dir`, replaying every segment to rebuild the
+    /// in-memory index.
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        let dir = data_dir.join(SEGMENTS_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            match id {
+                Some(id) if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) => {
+                    segments.push(id)
+                }
+                _ => log::warn!("Ignoring unexpected file {}", path.display()),
+            }
+        }
+        segments.sort_unstable();
+
+        let mut chunks = HashMap::new();
+        let mut metadata = HashMap::new();
+        let mut providers = HashMap::new();
+        for &segment in &segments {
+            let path = segment_path(&dir, segment);
+            let valid_len =
+                replay_segment(&path, segment, &mut chunks, &mut metadata, &mut providers)?;
+            if valid_len < fs::metadata(&path)?.len() {
+                log::warn!(
+                    "Truncating torn write at offset {} in {}",
+                    valid_len,
+                    path.display()
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(valid_len)?;
+                file.sync_all()?;
+            }
+        }
+
+        let segment = segments.last().copied().unwrap_or(0);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, segment))?;
+        let len = file.metadata()?.len();
+
+        log::info!(
+            "Recovered {} chunks, {} files and {} provider keys from {} segments in {}",
+            chunks.len(),
+            metadata.len(),
+            providers.len(),
+            segments.len(),
+            dir.display()
+        );
+
+        Ok(Self {
+            dir,
+            writer: Mutex::new(Writer { segment, file, len }),
+            chunks: RwLock::new(chunks),
+            metadata: RwLock::new(metadata),
+            providers: RwLock::new(providers),
+        })
+    }
+
+    /// Appends a record and fsyncs it, returning where its value was written.
+    fn append(&self, kind: u8, op: u8, key: &[u8], value: &[u8]) -> io::Result<Location> {
+        let location = self.write(kind, op, key, value)?;
+        self.sync()?;
+        Ok(location)
+    }
+
+    /// Appends a record without waiting for it to reach the disk, which
+    /// takes a `sync`. A segment is synced before writes move on to the
+    /// next, so syncing the current one covers every earlier write.
+    fn write(&self, kind: u8, op: u8, key: &[u8], value: &[u8]) -> io::Result<Location> {
+        let record = encode_record(kind, op, key, value);
+
+        let mut writer = self.writer.lock().unwrap();
+        if writer.len > 0 && writer.len + record.len() as u64 > MAX_SEGMENT_SIZE {
+            writer.file.sync_data()?;
+            let segment = writer.segment + 1;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_path(&self.dir, segment))?;
+            File::open(&self.dir)?.sync_all()?;
+            *writer = Writer {
+                segment,
+                file,
+                len: 0,
+            };
+        }
+
+        let offset = writer.len;
+        writer.file.write_all(&record)?;
+        writer.len += record.len() as u64;
+
+        Ok(Location {
+            segment: writer.segment,
+            offset: offset + (HEADER_LEN + key.len()) as u64,
+            len: value.len() as u32,
+        })
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().file.sync_data()
+    }
+
+    fn read_location(&self, location: Location) -> io::Result<Vec<u8>> {
+        let mut file = File::open(segment_path(&self.dir, location.segment))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut data = vec![0u8; location.len as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl StorageBackend for SegmentLogBackend {
+    fn store_chunk(&self, hash: &[u8], data: &[u8]) -> io::Result<()> {
+        let location = self.append(KIND_CHUNK, OP_PUT, hash, data)?;
+        self.chunks.write().unwrap().insert(hash.to_vec(), location);
+        Ok(())
+    }
+
+    fn get_chunk(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        let location = *self.chunks.read().unwrap().get(hash)?;
+        match self.read_location(location) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                log::error!("Failed to read chunk {}: {}", hex::encode(hash), e);
+                None
+            }
+        }
+    }
+
+    fn has_chunk(&self, hash: &[u8]) -> bool {
+        self.chunks.read().unwrap().contains_key(hash)
+    }
+
+    fn delete_chunk(&self, hash: &[u8]) -> io::Result<bool> {
+        if !self.has_chunk(hash) {
+            return Ok(false);
+        }
+        self.append(KIND_CHUNK, OP_DELETE, hash, &[])?;
+        Ok(self.chunks.write().unwrap().remove(hash).is_some())
+    }
+
+    fn chunk_hashes(&self) -> Vec<Vec<u8>> {
+        self.chunks.read().unwrap().keys().cloned().collect()
+    }
+
+    fn store_metadata(&self, hash: &[u8], encoded: &[u8]) -> io::Result<()> {
+        let metadata = decode_metadata(encoded)?;
+        self.append(KIND_METADATA, OP_PUT, hash, encoded)?;
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(hash.to_vec(), (metadata, encoded.to_vec()));
+        Ok(())
+    }
+
+    fn get_metadata(&self, hash: &[u8]) -> Option<FileInfo> {
+        Some(self.metadata.read().unwrap().get(hash)?.0.clone())
+    }
+
+    fn get_metadata_bytes(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        Some(self.metadata.read().unwrap().get(hash)?.1.clone())
+    }
+
+    fn metadata_entries(&self) -> Vec<(Vec<u8>, FileInfo)> {
+        self.metadata
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, (v, _))| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn add_provider(&self, key: &[u8], record: &ProviderRecord) -> io::Result<()> {
+        // hold the lock across the write so concurrent updates can't be
+        // lost, but not across the fsync, which would stall every lookup
+        {
+            let mut providers = self.providers.write().unwrap();
+            let mut records = providers.get(key).cloned().unwrap_or_default();
+            merge_provider(&mut records, record);
+            let encoded = bincode::serialize(&records)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.write(KIND_PROVIDERS, OP_PUT, key, &encoded)?;
+            providers.insert(key.to_vec(), records);
+        }
+        self.sync()
+    }
+
+    fn get_providers(&self, key: &[u8]) -> Vec<ProviderRecord> {
+        self.providers
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn remove_provider(&self, key: &[u8], node_id: &[u8; 32]) -> io::Result<bool> {
+        // like `add_provider`, the fsync happens once the lock is released
+        {
+            let mut providers = self.providers.write().unwrap();
+            let Some(mut records) = providers.get(key).cloned() else {
+                return Ok(false);
+            };
+            let before = records.len();
+            records.retain(|p| &p.node_id != node_id);
+            if records.len() == before {
+                return Ok(false);
+            }
+            if records.is_empty() {
+                self.write(KIND_PROVIDERS, OP_DELETE, key, &[])?;
+                providers.remove(key);
+            } else {
+                let encoded = bincode::serialize(&records)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                self.write(KIND_PROVIDERS, OP_PUT, key, &encoded)?;
+                providers.insert(key.to_vec(), records);
+            }
+        }
+        self.sync()?;
+        Ok(true)
+    }
+
+    fn provider_entries(&self) -> Vec<(Vec<u8>, Vec<ProviderRecord>)> {
+        self.providers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{:08}.{}", segment, SEGMENT_EXTENSION))
+}
+
+fn encode_record(kind: u8, op: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut record = vec![0u8; CHECKSUM_LEN];
+    record.push(kind);
+    record.push(op);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+    let checksum = hash(&record[CHECKSUM_LEN..]);
+    record[..CHECKSUM_LEN].copy_from_slice(&checksum[..CHECKSUM_LEN]);
+    record
+}
+
+/// Applies every intact record of a segment to the index and returns the
+/// length of the valid prefix; anything after it is a torn or corrupt write.
+fn replay_segment(
+    path: &Path,
+    segment: u64,
+    chunks: &mut HashMap<Vec<u8>, Location>,
+    metadata: &mut MetadataIndex,
+    providers: &mut HashMap<Vec<u8>, Vec<ProviderRecord>>,
+) -> io::Result<u64> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut offset = 0u64;
+
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        if !read_full(&mut reader, &mut header)? {
+            return Ok(offset);
+        }
+        let kind = header[CHECKSUM_LEN];
+        let op = header[CHECKSUM_LEN + 1];
+        let key_len = u32::from_le_bytes(header[10..14].try_into().unwrap()) as usize;
+        let value_len = u32::from_le_bytes(header[14..18].try_into().unwrap()) as usize;
+
+        // a corrupt header can claim a body longer than the file itself
+        if offset + (HEADER_LEN + key_len + value_len) as u64 > file_len {
+            return Ok(offset);
+        }
+        let mut body = vec![0u8; key_len + value_len];
+        if !read_full(&mut reader, &mut body)? {
+            return Ok(offset);
+        }
+        let mut checked = header[CHECKSUM_LEN..].to_vec();
+        checked.extend_from_slice(&body);
+        if hash(&checked)[..CHECKSUM_LEN] != header[..CHECKSUM_LEN] {
+            return Ok(offset);
+        }
+
+        let value = body.split_off(key_len);
+        let key = body;
+        let value_offset = offset + (HEADER_LEN + key_len) as u64;
+        offset = value_offset + value_len as u64;
+
+        match (kind, op) {
+            (KIND_CHUNK, OP_PUT) => {
+                let location = Location {
+                    segment,
+                    offset: value_offset,
+                    len: value_len as u32,
+                };
+                chunks.insert(key, location);
+            }
+            (KIND_CHUNK, OP_DELETE) => {
+                chunks.remove(&key);
+            }
+            (KIND_METADATA, OP_PUT) => match FileInfo::decode(&value) {
+                Ok(info) => {
+                    metadata.insert(key, (info, value));
+                }
+                Err(e) => log::warn!("Skipping unreadable metadata {}: {}", hex::encode(&key), e),
+            },
+            (KIND_PROVIDERS, OP_PUT) => match bincode::deserialize::<Vec<ProviderRecord>>(&value) {
+                Ok(records) => {
+                    providers.insert(key, records);
+                }
+                Err(e) => {
+                    log::warn!("Skipping unreadable providers {}: {}", hex::encode(&key), e)
+                }
+            },
+            (KIND_PROVIDERS, OP_DELETE) => {
+                providers.remove(&key);
+            }
+            _ => log::warn!("Skipping unknown record type {}/{}", kind, op),
+        }
+    }
+}
+
+/// Fills `buf` completely, returning `false` if the reader ran out first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ufs-segment-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn replaying_applies_writes_and_tombstones() {
+        let dir = test_dir("replay");
+        let record = ProviderRecord {
+            node_id: [7; 32],
+            address: "http://127.0.0.1:7001".to_string(),
+            timestamp: 1,
+            expires_at: 100,
+        };
+        let backend = SegmentLogBackend::open(&dir).unwrap();
+        backend.store_chunk(b"a", b"first").unwrap();
+        backend.store_chunk(b"a", b"second").unwrap();
+        backend.store_chunk(b"b", b"deleted").unwrap();
+        backend.add_provider(b"key", &record).unwrap();
+        backend.add_provider(b"gone", &record).unwrap();
+        assert!(backend.delete_chunk(b"b").unwrap());
+        assert!(backend.remove_provider(b"gone", &record.node_id).unwrap());
+        drop(backend);
+
+        let backend = SegmentLogBackend::open(&dir).unwrap();
+        assert_eq!(backend.get_chunk(b"a"), Some(b"second".to_vec()));
+        assert!(!backend.has_chunk(b"b"));
+        assert_eq!(backend.get_providers(b"key"), vec![record]);
+        assert!(backend.get_providers(b"gone").is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let dir = test_dir("torn");
+        let backend = SegmentLogBackend::open(&dir).unwrap();
+        backend.store_chunk(b"a", b"kept").unwrap();
+        let intact_len = backend.writer.lock().unwrap().len;
+        backend.store_chunk(b"b", b"torn").unwrap();
+        drop(backend);
+
+        // lose the last bytes of the second record, as a crash mid-write would
+        let path = segment_path(&dir.join(SEGMENTS_DIR), 0);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(fs::metadata(&path).unwrap().len() - 2)
+            .unwrap();
+        drop(file);
+
+        let backend = SegmentLogBackend::open(&dir).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact_len);
+        assert_eq!(backend.get_chunk(b"a"), Some(b"kept".to_vec()));
+        assert!(!backend.has_chunk(b"b"));
+
+        // new writes land after the intact prefix and survive another replay
+        backend.store_chunk(b"c", b"after").unwrap();
+        drop(backend);
+        let backend = SegmentLogBackend::open(&dir).unwrap();
+        assert_eq!(backend.get_chunk(b"a"), Some(b"kept".to_vec()));
+        assert_eq!(backend.get_chunk(b"c"), Some(b"after".to_vec()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! Helpers for tests that need real nodes talking to each other.
+
+use crate::auth::Authenticator;
+use crate::dht::Peer;
+use crate::identity::Identity;
+use crate::node::{Node, NodeConfig};
+use crate::server::PeerServer;
+use crate::storage::MemoryBackend;
+use crate::storage_proto::peer_service_server::PeerServiceServer;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+
+/// A node config with a short RPC timeout, so tests against dead peers
+/// finish quickly.
+pub fn test_config() -> NodeConfig {
+    NodeConfig {
+        rpc_timeout: Duration::from_secs(2),
+        ..NodeConfig::default()
+    }
+}
+
+/// Starts a node with in-memory storage serving the peer RPCs on a free
+/// loopback port. It serves until the test's runtime shuts down.
+pub async fn spawn_node(config: NodeConfig) -> Node {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let node = Node::new(
+        listener.local_addr().unwrap(),
+        None,
+        Identity::generate().unwrap(),
+        Arc::new(MemoryBackend::new()),
+        config,
+    )
+    .unwrap();
+    let service = PeerServiceServer::with_interceptor(
+        PeerServer::new(Arc::new(node.clone())),
+        Authenticator::new(),
+    );
+    tokio::spawn(
+        Server::builder()
+            .add_service(service)
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    node
+}
+
+/// The routing table entry other nodes keep for `node`.
+pub fn peer_of(node: &Node) -> Peer {
+    Peer {
+        node_id: node.id,
+        address: node.address().unwrap(),
+        public_key: node.identity.public_key(),
+    }
+}
+
+/// Makes every node know every other one.
+pub async fn connect(nodes: &[&Node]) {
+    for a in nodes {
+        for b in nodes {
+            if a.id != b.id {
+                a.add_peer(peer_of(b)).await;
+            }
+        }
+    }
+}
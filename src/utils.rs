@@ -1,7 +1,55 @@
 use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
 
 pub fn hash(data: &[u8]) -> Vec<u8> {
     let mut hasher = Sha256::new();
     hasher.update(data);
     hasher.finalize().to_vec()
 }
+
+/// Seconds since the unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Ticks every `period`, starting one period from now.
+pub fn ticker(period: Duration) -> Interval {
+    let mut ticker = interval_at(Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
+
+/// Waits for the next tick of a background task, returning `false` instead if
+/// the node is shutting down.
+pub async fn tick_or_shutdown(ticker: &mut Interval, shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = ticker.tick() => true,
+        _ = shutdown.changed() => false,
+    }
+}
+
+/// Checks that `addr` is an `http://host:port` URL other peers could dial,
+/// refusing unspecified hosts such as `0.0.0.0`.
+pub fn check_peer_address(addr: &str) -> Result<(), String> {
+    let rest = addr
+        .strip_prefix("http://")
+        .or_else(|| addr.strip_prefix("https://"))
+        .ok_or_else(|| format!("{} must start with http:// or https://", addr))?;
+    let (host, port) = rest
+        .rsplit_once(':')
+        .ok_or_else(|| format!("{} has no port", addr))?;
+    if !port.parse::<u16>().is_ok_and(|port| port != 0) {
+        return Err(format!("{} has an invalid port", addr));
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified()) {
+        return Err(format!("{} is not an address peers can reach", addr));
+    }
+    Ok(())
+}